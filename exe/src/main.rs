@@ -1,22 +1,34 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use daemonize::Daemonize;
 use env_logger::Env;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use log::{error, info, LevelFilter};
 use os_pipe::{PipeReader, PipeWriter};
 use puzzlefs_lib::{
-    builder::{add_rootfs_delta, build_initial_rootfs, enable_fs_verity},
-    compression::{Noop, Zstd},
-    extractor::extract_rootfs,
+    builder::{
+        add_overlay_delta, add_rootfs_delta_from_tar, add_rootfs_delta_with_report,
+        build_initial_rootfs_from_tar, build_initial_rootfs_with_report, convert_oci_image,
+        enable_fs_verity, rebase_rootfs, squash_rootfs, BuildObserver, BuildOptions, BuildReport,
+        Canonicalization, ChunkAlgorithm, ChunkParams, IdMap, IdMapping, OwnerOverride,
+        TimestampPolicy,
+    },
+    compression::{Compression, Noop, Zstd},
+    exporter::export_tar,
+    extractor::{extract_rootfs, ExtractOptions},
     fsverity_helpers::get_fs_verity_digest,
-    oci::Image,
+    oci::{Descriptor, Image},
     reader::{fuse::PipeDescriptor, mount, spawn_mount},
 };
+use std::any::Any;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use syslog::{BasicLogger, Facility, Formatter3164};
 
 #[derive(Parser)]
@@ -29,8 +41,12 @@ struct Opts {
 #[derive(Subcommand)]
 enum SubCommand {
     Build(Build),
+    Convert(Convert),
+    Squash(Squash),
+    Rebase(Rebase),
     Mount(Mount),
     Extract(Extract),
+    Export(Export),
     EnableFsVerity(FsVerity),
 }
 
@@ -43,6 +59,185 @@ struct Build {
     base_layer: Option<String>,
     #[arg(short, long, value_name = "compressed")]
     compression: bool,
+    #[command(flatten)]
+    chunking: Chunking,
+    #[command(flatten)]
+    filters: Filters,
+    #[command(flatten)]
+    ownership: Ownership,
+    #[command(flatten)]
+    canonical: Canonical,
+    /// Compare the contents of the files that look unchanged since the base layer
+    #[arg(long)]
+    verify_unchanged_files: bool,
+    /// Include the contents of the directories that are on other filesystems, e.g. bind mounts
+    #[arg(long)]
+    cross_filesystems: bool,
+    /// The rootfs is an overlayfs upper directory with the changes to the base layer
+    #[arg(long, requires = "base_layer")]
+    overlay: bool,
+    /// Print the manifest digest and the build report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Convert {
+    oci_layout: String,
+    oci_tag: String,
+    oci_dir: String,
+    tag: String,
+    #[arg(short, long, value_name = "compressed")]
+    compression: bool,
+    #[command(flatten)]
+    chunking: Chunking,
+    #[command(flatten)]
+    filters: Filters,
+    #[command(flatten)]
+    ownership: Ownership,
+    #[command(flatten)]
+    canonical: Canonical,
+    /// Print the manifest digest and the reports of the layers as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Flatten the layers of an image into a single layer, reusing its chunks
+#[derive(Args)]
+struct Squash {
+    oci_dir: String,
+    tag: String,
+    new_tag: String,
+    /// Print the manifest digest and the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Move the layers that an image has on top of a base image onto another base image
+#[derive(Args)]
+struct Rebase {
+    oci_dir: String,
+    tag: String,
+    old_base: String,
+    new_base: String,
+    new_tag: String,
+    /// Print the manifest digest and the reports of the layers as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+struct Chunking {
+    #[arg(long, value_name = "fastcdc|fixed|buzhash")]
+    chunker: Option<ChunkAlgorithm>,
+    #[arg(long, value_name = "bytes")]
+    min_chunk_size: Option<u32>,
+    #[arg(long, value_name = "bytes")]
+    avg_chunk_size: Option<u32>,
+    #[arg(long, value_name = "bytes")]
+    max_chunk_size: Option<u32>,
+}
+
+impl Chunking {
+    // the parameters that aren't given are the ones of the base image, so that e.g. only the
+    // average size can be changed
+    fn build_options(&self, base: ChunkParams) -> BuildOptions {
+        if self.chunker.is_none()
+            && self.min_chunk_size.is_none()
+            && self.avg_chunk_size.is_none()
+            && self.max_chunk_size.is_none()
+        {
+            return BuildOptions::default();
+        }
+
+        BuildOptions {
+            chunk_params: Some(ChunkParams {
+                algorithm: self.chunker.unwrap_or(base.algorithm),
+                min_size: self.min_chunk_size.unwrap_or(base.min_size),
+                avg_size: self.avg_chunk_size.unwrap_or(base.avg_size),
+                max_size: self.max_chunk_size.unwrap_or(base.max_size),
+            }),
+            ..BuildOptions::default()
+        }
+    }
+}
+
+#[derive(Args)]
+struct Filters {
+    /// Leave out the paths matching a .gitignore style pattern
+    #[arg(long, value_name = "pattern")]
+    exclude: Vec<String>,
+    /// Keep the paths matching a .gitignore style pattern, even if they are excluded
+    #[arg(long, value_name = "pattern")]
+    include: Vec<String>,
+}
+
+impl Filters {
+    // the includes come last so that they override the excludes, whatever the order of the flags
+    fn rules(&self) -> Vec<String> {
+        self.exclude
+            .iter()
+            .cloned()
+            .chain(self.include.iter().map(|pattern| format!("!{pattern}")))
+            .collect()
+    }
+}
+
+#[derive(Args)]
+struct Ownership {
+    /// Map the owners of the files like a line of a user namespace's uid_map, can be repeated
+    #[arg(long, value_name = "inside outside count")]
+    uid_map: Vec<IdMapping>,
+    /// Map the groups of the files like a line of a user namespace's gid_map, can be repeated
+    #[arg(long, value_name = "inside outside count")]
+    gid_map: Vec<IdMapping>,
+    /// Give everything at and below a path of the image the same owner
+    #[arg(long, value_name = "path=uid:gid")]
+    force_owner: Vec<OwnerOverride>,
+}
+
+impl Ownership {
+    fn build_options(&self, options: BuildOptions) -> std::io::Result<BuildOptions> {
+        Ok(BuildOptions {
+            uid_map: IdMap::new(self.uid_map.clone())?,
+            gid_map: IdMap::new(self.gid_map.clone())?,
+            force_owner: self.force_owner.clone(),
+            ..options
+        })
+    }
+}
+
+#[derive(Args)]
+struct Canonical {
+    /// Zero the timestamps (or clamp them to SOURCE_DATE_EPOCH) and drop the security.selinux
+    /// xattrs, so that the image only depends on the contents of the files
+    #[arg(long)]
+    canonical: bool,
+    /// Make root the owner of all the files, implies --canonical
+    #[arg(long)]
+    canonical_ownership: bool,
+    /// Give all the files 0644 or 0755 permissions, keeping the setuid, setgid and sticky bits;
+    /// implies --canonical
+    #[arg(long)]
+    canonical_permissions: bool,
+}
+
+impl Canonical {
+    fn build_options(&self, options: BuildOptions) -> std::io::Result<BuildOptions> {
+        if !self.canonical && !self.canonical_ownership && !self.canonical_permissions {
+            return Ok(options);
+        }
+
+        Ok(BuildOptions {
+            canonicalization: Some(Canonicalization {
+                timestamps: TimestampPolicy::from_env()?,
+                normalize_ownership: self.canonical_ownership,
+                normalize_permissions: self.canonical_permissions,
+                ..Canonicalization::default()
+            }),
+            ..options
+        })
+    }
 }
 
 #[derive(Args)]
@@ -65,6 +260,30 @@ struct Extract {
     oci_dir: String,
     tag: String,
     extract_dir: String,
+    /// Only extract these paths of the image or the ones matching these glob patterns, e.g.
+    /// /etc or '/usr/bin/*sh', with their parent directories
+    paths: Vec<String>,
+    /// Write the contents of the files with one thread per CPU
+    #[arg(short, long)]
+    parallel: bool,
+}
+
+#[derive(Clone, ValueEnum)]
+enum ExportFormat {
+    /// A POSIX (pax) tar archive
+    Tar,
+}
+
+/// Write the rootfs of an image as an archive, without extracting it
+#[derive(Args)]
+struct Export {
+    oci_dir: String,
+    tag: String,
+    /// The file to write the archive to, or - for the standard output
+    #[arg(default_value = "-")]
+    output: String,
+    #[arg(short, long, value_enum, default_value_t = ExportFormat::Tar)]
+    format: ExportFormat,
 }
 
 #[derive(Args)]
@@ -148,42 +367,246 @@ fn mount_background(
     Ok(())
 }
 
+// shows the progress of a build on stderr, when it is a terminal: the number of files walked, then
+// how much of the files was chunked and how much of it wasn't already in the image
+struct BuildProgress {
+    bar: ProgressBar,
+    stored: AtomicU64,
+    deduplicated: AtomicU64,
+}
+
+impl BuildProgress {
+    fn new() -> Self {
+        let bar = ProgressBar::new_spinner().with_style(
+            ProgressStyle::with_template("{spinner} walked {human_pos} files").unwrap(),
+        );
+        bar.enable_steady_tick(Duration::from_millis(100));
+        BuildProgress {
+            bar,
+            stored: AtomicU64::new(0),
+            deduplicated: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BuildObserver for BuildProgress {
+    fn file_walked(&self, _path: &Path) {
+        self.bar.inc(1);
+    }
+
+    fn chunking_started(&self, total: u64) {
+        self.bar.set_style(
+            ProgressStyle::with_template("[{elapsed}] {wide_bar} {bytes}/{total_bytes} {msg}")
+                .unwrap(),
+        );
+        self.bar.set_position(0);
+        self.bar.set_length(total);
+    }
+
+    fn bytes_chunked(&self, len: u64) {
+        self.bar.inc(len);
+    }
+
+    fn chunk_stored(&self, len: u64, deduplicated: bool) {
+        let counter = if deduplicated {
+            &self.deduplicated
+        } else {
+            &self.stored
+        };
+        counter.fetch_add(len, Ordering::Relaxed);
+        self.bar.set_message(format!(
+            "({} new, {} deduplicated)",
+            HumanBytes(self.stored.load(Ordering::Relaxed)),
+            HumanBytes(self.deduplicated.load(Ordering::Relaxed))
+        ));
+    }
+}
+
+// the rootfs is either a directory, a tar archive or "-" for a tar archive read from stdin
+fn build<C: Compression + Any>(
+    b: &Build,
+    image: Image,
+) -> anyhow::Result<(Descriptor, Arc<Image>, BuildReport)> {
+    let rootfs = b.rootfs.as_str();
+    let base_layer = b.base_layer.as_deref();
+    let base_params = match base_layer {
+        Some(base_layer) => {
+            image
+                .open_rootfs_blob::<Noop>(base_layer, None)?
+                .chunk_params
+        }
+        None => ChunkParams::default(),
+    };
+    let options = b.ownership.build_options(BuildOptions {
+        verify_unchanged_files: b.verify_unchanged_files,
+        cross_filesystems: b.cross_filesystems,
+        exclude: b.filters.rules(),
+        ..b.chunking.build_options(base_params)
+    })?;
+    let options = b.canonical.build_options(options)?;
+    let progress = Arc::new(BuildProgress::new());
+    let options = BuildOptions {
+        observer: Some(progress.clone()),
+        ..options
+    };
+
+    // stop at the next chunk on ctrl-c, without tagging the partial image
+    let cancellation = options.cancellation.clone();
+    ctrlc::set_handler(move || cancellation.cancel())?;
+
+    let archive: Option<Box<dyn Read>> = if rootfs == "-" {
+        Some(Box::new(std::io::stdin().lock()))
+    } else if fs::metadata(rootfs)?.is_file() {
+        Some(Box::new(BufReader::new(fs::File::open(rootfs)?)))
+    } else {
+        None
+    };
+
+    let built = match (archive, base_layer) {
+        (None, Some(base_layer)) if b.overlay => {
+            add_overlay_delta::<C>(Path::new(rootfs), image, base_layer, &options)
+        }
+        (Some(archive), Some(base_layer)) => {
+            add_rootfs_delta_from_tar::<C>(archive, image, base_layer, &options)
+        }
+        (Some(archive), None) => build_initial_rootfs_from_tar::<C>(archive, &image, &options)
+            .map(|(desc, report)| (desc, Arc::new(image), report)),
+        (None, Some(base_layer)) => {
+            add_rootfs_delta_with_report::<C>(Path::new(rootfs), image, base_layer, &options)
+        }
+        (None, None) => build_initial_rootfs_with_report::<C>(Path::new(rootfs), &image, &options)
+            .map(|(desc, report)| (desc, Arc::new(image), report)),
+    };
+    progress.bar.finish_and_clear();
+    Ok(built?)
+}
+
+fn manifest_digest(image: &Image, tag: &str) -> anyhow::Result<String> {
+    let mut manifest_fd = image.get_image_manifest_fd(tag)?;
+    let mut read_buffer = Vec::new();
+    manifest_fd.read_to_end(&mut read_buffer)?;
+    Ok(hex::encode(get_fs_verity_digest(&read_buffer)?))
+}
+
+fn print_manifest_digest(image: &Image, tag: &str) -> anyhow::Result<()> {
+    println!(
+        "puzzlefs image manifest digest: {}",
+        manifest_digest(image, tag)?
+    );
+    Ok(())
+}
+
+// the digest stays on the last line, where scripts expect it
+fn print_build_report(report: &BuildReport) {
+    println!(
+        "inodes: {} files, {} directories, {} other, {} whiteouts",
+        report.files, report.directories, report.other_inodes, report.whiteouts
+    );
+    println!("file contents: {}", HumanBytes(report.logical_bytes));
+    println!(
+        "chunks: {}, {} uncompressed, {} compressed",
+        report.chunks,
+        HumanBytes(report.uncompressed_bytes),
+        HumanBytes(report.compressed_bytes)
+    );
+    println!(
+        "blobs: {} new, {} already in the image",
+        report.new_blobs, report.existing_blobs
+    );
+    println!("metadata: {}", HumanBytes(report.metadata_bytes));
+}
+
+fn print_build_result(
+    image: &Image,
+    tag: &str,
+    report: &BuildReport,
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        let digest = manifest_digest(image, tag)?;
+        println!(
+            "{}",
+            serde_json::json!({"manifest_digest": digest, "report": report})
+        );
+        Ok(())
+    } else {
+        print_build_report(report);
+        print_manifest_digest(image, tag)
+    }
+}
+
+// convert and rebase build several layers, whose reports are listed from the bottom up
+fn print_layers_result(
+    image: &Image,
+    tag: &str,
+    reports: &[BuildReport],
+    json: bool,
+) -> anyhow::Result<()> {
+    if json {
+        let digest = manifest_digest(image, tag)?;
+        println!(
+            "{}",
+            serde_json::json!({"manifest_digest": digest, "layers": reports})
+        );
+        Ok(())
+    } else {
+        for (i, report) in reports.iter().enumerate() {
+            println!("layer {}:", i + 1);
+            print_build_report(report);
+        }
+        print_manifest_digest(image, tag)
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
     match opts.subcmd {
         SubCommand::Build(b) => {
-            let rootfs = Path::new(&b.rootfs);
+            init_logging("warn");
             let oci_dir = Path::new(&b.oci_dir);
             let image = Image::new(oci_dir)?;
-            let new_image = match b.base_layer {
-                Some(base_layer) => {
-                    let (desc, image) = if b.compression {
-                        add_rootfs_delta::<Zstd>(rootfs, image, &base_layer)?
-                    } else {
-                        add_rootfs_delta::<Noop>(rootfs, image, &base_layer)?
-                    };
-                    image.add_tag(&b.tag, desc)?;
-                    image
-                }
-                None => {
-                    let desc = if b.compression {
-                        build_initial_rootfs::<Zstd>(rootfs, &image)?
-                    } else {
-                        build_initial_rootfs::<Noop>(rootfs, &image)?
-                    };
-                    image.add_tag(&b.tag, desc)?;
-                    Arc::new(image)
-                }
+            let (desc, new_image, report) = if b.compression {
+                build::<Zstd>(&b, image)?
+            } else {
+                build::<Noop>(&b, image)?
             };
-            let mut manifest_fd = new_image.get_image_manifest_fd(&b.tag)?;
-            let mut read_buffer = Vec::new();
-            manifest_fd.read_to_end(&mut read_buffer)?;
-            let manifest_digest = get_fs_verity_digest(&read_buffer)?;
-            println!(
-                "puzzlefs image manifest digest: {}",
-                hex::encode(manifest_digest)
-            );
-            Ok(())
+            new_image.add_tag(&b.tag, desc)?;
+            print_build_result(&new_image, &b.tag, &report, b.json)
+        }
+        SubCommand::Convert(c) => {
+            init_logging("warn");
+            let oci_layout = Path::new(&c.oci_layout);
+            let image = Image::new(Path::new(&c.oci_dir))?;
+            let options = c.ownership.build_options(BuildOptions {
+                exclude: c.filters.rules(),
+                ..c.chunking.build_options(ChunkParams::default())
+            })?;
+            let options = c.canonical.build_options(options)?;
+            let (desc, image, reports) = if c.compression {
+                convert_oci_image::<Zstd>(oci_layout, &c.oci_tag, image, &options)?
+            } else {
+                convert_oci_image::<Noop>(oci_layout, &c.oci_tag, image, &options)?
+            };
+            image.add_tag(&c.tag, desc)?;
+            print_layers_result(&image, &c.tag, &reports, c.json)
+        }
+        SubCommand::Squash(s) => {
+            let image = Image::open(Path::new(&s.oci_dir))?;
+            let (desc, image, report) = squash_rootfs(image, &s.tag, &BuildOptions::default())?;
+            image.add_tag(&s.new_tag, desc)?;
+            print_build_result(&image, &s.new_tag, &report, s.json)
+        }
+        SubCommand::Rebase(r) => {
+            let image = Image::open(Path::new(&r.oci_dir))?;
+            let (desc, image, reports) = rebase_rootfs(
+                image,
+                &r.tag,
+                &r.old_base,
+                &r.new_base,
+                &BuildOptions::default(),
+            )?;
+            image.add_tag(&r.new_tag, desc)?;
+            print_layers_result(&image, &r.new_tag, &reports, r.json)
         }
         SubCommand::Mount(m) => {
             let log_level = "info";
@@ -265,7 +688,22 @@ fn main() -> anyhow::Result<()> {
         }
         SubCommand::Extract(e) => {
             init_logging("info");
-            extract_rootfs(&e.oci_dir, &e.tag, &e.extract_dir)
+            let options = ExtractOptions {
+                parallel: e.parallel,
+                paths: e.paths,
+            };
+            extract_rootfs(&e.oci_dir, &e.tag, &e.extract_dir, &options)
+        }
+        SubCommand::Export(e) => {
+            init_logging("info");
+            let output: Box<dyn Write> = if e.output == "-" {
+                Box::new(std::io::stdout().lock())
+            } else {
+                Box::new(fs::File::create(&e.output)?)
+            };
+            match e.format {
+                ExportFormat::Tar => export_tar(&e.oci_dir, &e.tag, BufWriter::new(output)),
+            }
         }
         SubCommand::EnableFsVerity(v) => {
             let oci_dir = Path::new(&v.oci_dir);
@@ -1,4 +1,4 @@
-use crate::common::{AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::common::MAX_METADATA_SHARD_SIZE;
 use crate::compression::{Compression, Noop, Zstd};
 use crate::fsverity_helpers::{
     check_fs_verity, fsverity_enable, get_fs_verity_digest, InnerHashAlgorithm,
@@ -7,74 +7,238 @@ use crate::fsverity_helpers::{
 use crate::oci::Digest;
 use std::any::Any;
 use std::cmp::min;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::fs;
-use std::io;
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
 use std::os::fd::AsRawFd;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
-use std::sync::Arc;
-
-use walkdir::WalkDir;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
 use crate::format::{
-    BlobRef, DirEnt, DirList, FileChunk, FileChunkList, Ino, Inode, InodeAdditional, InodeMode,
-    Result, Rootfs, VerityData, WireFormatError,
+    BlobRef, DirEnt, DirList, FileChunk, Ino, Inode, InodeMode, MetadataShard, Result, Rootfs,
+    VerityData, WireFormatError, SHA256_BLOCK_SIZE,
 };
 use crate::oci::media_types;
 use crate::oci::{Descriptor, Image};
-use crate::reader::{PuzzleFS, PUZZLEFS_IMAGE_MANIFEST_VERSION};
+use crate::reader::{FileReader, PuzzleFS, PUZZLEFS_IMAGE_MANIFEST_VERSION};
 use crate::{manifest_capnp, metadata_capnp};
 
-use nix::errno::Errno;
-
-use fastcdc::v2020::StreamCDC;
+mod archive;
+mod chunker;
+use chunker::Chunks;
+mod convert;
+mod exclude;
+pub use crate::format::{
+    Canonicalization, ChunkAlgorithm, ChunkParams, Timespec, TimestampPolicy, Xattr,
+};
+pub use convert::convert_oci_image;
+use exclude::Excludes;
 mod filesystem;
+mod overlay;
+pub use overlay::add_overlay_delta;
+mod ownership;
 use filesystem::FilesystemStream;
+pub use ownership::{IdMap, IdMapping, OwnerOverride};
+mod progress;
+pub use progress::{BuildObserver, BuildReport, CancellationToken};
+mod rebase;
+pub use rebase::rebase_rootfs;
+mod squash;
+pub use squash::squash_rootfs;
+mod tree;
+use tree::{Content, Node, NodeId, Tree, ROOT};
+mod tree_builder;
+pub use tree_builder::{EntryMetadata, TreeBuilder};
+
+/// Options for building a puzzlefs image.
+#[derive(Debug, Default, Clone)]
+pub struct BuildOptions {
+    /// The chunk sizes to use; by default, a delta uses the ones of the image it is added to and a
+    /// new image uses [ChunkParams::default].
+    pub chunk_params: Option<ChunkParams>,
+    /// The approximate maximum size of a metadata blob, in bytes; the metadata of larger layers is
+    /// split into several blobs. Defaults to 4MiB.
+    pub max_metadata_shard_size: Option<u64>,
+    /// A delta reuses the chunks of the files whose size and modification time are the same as in
+    /// the image it is added to. When set, the contents of these files are also compared, which
+    /// is slower but catches changes that preserve the modification time.
+    pub verify_unchanged_files: bool,
+    /// Paths to leave out of the image, as lines of a .gitignore file: `!` includes paths
+    /// excluded by earlier rules. When building from a directory, these rules come after the ones
+    /// in its `.puzzlefsignore` file. A delta hides the excluded paths that are in the image it is
+    /// added to.
+    pub exclude: Vec<String>,
+    /// Maps the owners of the files of the root filesystem to the owners in the image, e.g. to
+    /// undo the shift of the ids of a root filesystem unpacked in a user namespace.
+    pub uid_map: IdMap,
+    pub gid_map: IdMap,
+    /// Gives whole subtrees a fixed owner, regardless of the id maps; the most specific override
+    /// of a path wins.
+    pub force_owner: Vec<OwnerOverride>,
+    /// Normalizes the metadata of the inodes, so that the image only depends on the contents of
+    /// the files and on the policy, which is recorded in the image. Like the chunk sizes, a delta
+    /// uses the policy of the image it is added to by default.
+    pub canonicalization: Option<Canonicalization>,
+    /// Walks into the directories of the root filesystem that are on other filesystems, e.g. bind
+    /// mounts or a tmpfs; by default, they are empty directories in the image and a warning is
+    /// logged for each one.
+    pub cross_filesystems: bool,
+    /// Notified of the progress of the build.
+    pub observer: Option<Arc<dyn BuildObserver>>,
+    /// Stops the build when cancelled.
+    pub cancellation: CancellationToken,
+}
 
-fn walker(rootfs: &Path) -> WalkDir {
-    // breadth first search for sharing, don't cross filesystems just to be safe, order by file
-    // name. we only return directories here, so we can more easily do delta generation to detect
-    // what's missing in an existing puzzlefs.
-    WalkDir::new(rootfs)
-        .contents_first(false)
-        .follow_links(false)
-        .same_file_system(true)
-        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
-}
-
-// a struct to hold a directory's information before it can be rendered into a InodeSpecific::Dir
-// (aka the offset is unknown because we haven't accumulated all the inodes yet)
-struct Dir {
-    ino: u64,
-    dir_list: DirList,
-    md: fs::Metadata,
-    additional: Option<InodeAdditional>,
-}
-
-impl Dir {
-    fn add_entry(&mut self, name: OsString, ino: Ino) {
-        self.dir_list.entries.push(DirEnt {
-            name: OsString::into_vec(name),
-            ino,
-        });
+impl BuildOptions {
+    pub(crate) fn notify(&self, event: impl FnOnce(&dyn BuildObserver)) {
+        if let Some(observer) = &self.observer {
+            event(observer.as_ref());
+        }
     }
 }
 
-// similar to the above, but holding file metadata
+// a regular file whose chunks are filled in once the whole layer has been chunked
 struct File {
-    ino: u64,
-    chunk_list: FileChunkList,
-    md: fs::Metadata,
-    additional: Option<InodeAdditional>,
+    // the size of the data of the file in the chunk stream
+    size: u64,
+    // where that data is in a sparse file, the rest being holes, and the size of the file
+    sparse: Option<(Vec<(u64, u64)>, u64)>,
+    chunks: Vec<FileChunk>,
+    existing: Option<Inode>,
+    // the rendered inode, without its chunks
+    inode: Inode,
+}
+
+fn render(
+    node: &Node,
+    ino: Ino,
+    nlink: u32,
+    mode: InodeMode,
+    (uid, gid): (u32, u32),
+    canonicalization: Option<&Canonicalization>,
+) -> Inode {
+    let mut inode = Inode {
+        ino,
+        mode,
+        uid,
+        gid,
+        nlink,
+        ..node.inode.clone()
+    };
+    if let Some(canonicalization) = canonicalization {
+        canonicalization.apply(&mut inode);
+    }
+    inode
+}
+
+// a delta only contains the inodes that are different from the ones in the layers below. Building
+// the layer below read the files, which bumped their atime, and the ctime changes along with the
+// rest of the metadata, so neither of them makes an inode different on its own
+fn is_changed(inode: &Inode, existing: Option<&Inode>) -> bool {
+    let Some(Inode {
+        ino,
+        mode,
+        uid,
+        gid,
+        permissions,
+        atime: _,
+        mtime,
+        ctime: _,
+        nlink,
+        additional,
+    }) = existing
+    else {
+        return true;
+    };
+    (
+        *ino,
+        mode,
+        *uid,
+        *gid,
+        *permissions,
+        *mtime,
+        *nlink,
+        additional,
+    ) != (
+        inode.ino,
+        &inode.mode,
+        inode.uid,
+        inode.gid,
+        inode.permissions,
+        inode.mtime,
+        inode.nlink,
+        &inode.additional,
+    )
+}
+
+fn push_if_changed(pfs_inodes: &mut Vec<Inode>, inode: Inode, existing: Option<&Inode>) {
+    if is_changed(&inode, existing) {
+        pfs_inodes.push(inode);
+    }
+}
+
+fn same_contents(mut a: impl Read, mut b: impl Read) -> io::Result<bool> {
+    const BLOCK_SIZE: u64 = 1024 * 1024;
+    let (mut block_a, mut block_b) = (Vec::new(), Vec::new());
+    loop {
+        block_a.clear();
+        block_b.clear();
+        a.by_ref().take(BLOCK_SIZE).read_to_end(&mut block_a)?;
+        b.by_ref().take(BLOCK_SIZE).read_to_end(&mut block_b)?;
+        if block_a != block_b {
+            return Ok(false);
+        }
+        if block_a.is_empty() {
+            return Ok(true);
+        }
+    }
 }
 
-struct Other {
-    ino: u64,
-    md: fs::Metadata,
-    additional: Option<InodeAdditional>,
+// returns the chunks of the existing file if the file in the tree has the same contents, so it
+// doesn't need to be chunked again; files that come from an image keep their own chunks
+fn reusable_chunks(
+    tree: &Tree,
+    node: NodeId,
+    inode: &Inode,
+    existing: Option<&Inode>,
+    pfs: Option<&PuzzleFS>,
+    options: &BuildOptions,
+) -> Result<Option<Vec<FileChunk>>> {
+    if let Some(Content::Image { chunks }) = &tree.node(node).content {
+        return Ok(Some(chunks.clone()));
+    }
+    let (Some(existing), Some(pfs)) = (existing, pfs) else {
+        return Ok(None);
+    };
+    let InodeMode::File { chunks } = &existing.mode else {
+        return Ok(None);
+    };
+
+    // a tree that was rendered as the existing layer knows which of its files were replaced since
+    if let Some(rendered) = tree.rendered() {
+        let unchanged = rendered.get(&node) == Some(&existing.ino);
+        return Ok(unchanged.then(|| chunks.clone()));
+    }
+
+    // a canonical modification time may hide a change, e.g. all the files modified after the
+    // clamping time have the same one, so these files are always compared
+    let canonical_mtime = inode.mtime != tree.node(node).inode.mtime;
+    if existing.file_len()? != tree.node(node).size || existing.mtime != inode.mtime {
+        return Ok(None);
+    }
+    if (options.verify_unchanged_files || canonical_mtime)
+        && !same_contents(
+            tree.open_content(node)?,
+            FileReader::new(&pfs.oci, existing)?,
+        )?
+    {
+        return Ok(None);
+    }
+    Ok(Some(chunks.clone()))
 }
 
 fn serialize_manifest(rootfs: Rootfs) -> Result<Vec<u8>> {
@@ -88,7 +252,7 @@ fn serialize_manifest(rootfs: Rootfs) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
-fn serialize_metadata(inodes: Vec<Inode>) -> Result<Vec<u8>> {
+fn serialize_metadata(inodes: &[Inode]) -> Result<Vec<u8>> {
     let mut message = ::capnp::message::Builder::new_default();
     let capnp_inode_vector = message.init_root::<metadata_capnp::inode_vector::Builder<'_>>();
     let inodes_len = inodes.len().try_into()?;
@@ -106,60 +270,318 @@ fn serialize_metadata(inodes: Vec<Inode>) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+fn serialize_shard_index(shards: &[MetadataShard]) -> Result<Vec<u8>> {
+    let mut message = ::capnp::message::Builder::new_default();
+    let capnp_inode_vector = message.init_root::<metadata_capnp::inode_vector::Builder<'_>>();
+    let mut capnp_shards = capnp_inode_vector.init_shards(shards.len().try_into()?);
+
+    for (i, shard) in shards.iter().enumerate() {
+        // we already checked that the number of shards fits inside a u32
+        shard.fill_capnp(&mut capnp_shards.reborrow().get(i as u32));
+    }
+
+    let mut buf = Vec::new();
+    ::capnp::serialize::write_message(&mut buf, &message)?;
+    Ok(buf)
+}
+
+fn serialized_inode_size(inode: &Inode) -> Result<u64> {
+    let allocator = ::capnp::message::HeapAllocator::new().first_segment_words(64);
+    let mut message = ::capnp::message::Builder::new(allocator);
+    inode.fill_capnp(&mut message.init_root::<metadata_capnp::inode::Builder<'_>>())?;
+    Ok(message
+        .get_segments_for_output()
+        .iter()
+        .map(|segment| segment.len() as u64)
+        .sum())
+}
+
+// splits the inodes, sorted by inode number, into runs that serialize to at most max_size bytes
+// (unless a single inode is larger than that)
+fn shard_inodes(inodes: &[Inode], max_size: u64) -> Result<Vec<&[Inode]>> {
+    let mut shards = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, inode) in inodes.iter().enumerate() {
+        let inode_size = serialized_inode_size(inode)?;
+        if i > start && size + inode_size > max_size {
+            shards.push(&inodes[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += inode_size;
+    }
+    shards.push(&inodes[start..]);
+    Ok(shards)
+}
+
+fn put_metadata_blob(
+    oci: &Image,
+    buf: &[u8],
+    verity_data: &mut VerityData,
+    report: &mut BuildReport,
+    options: &BuildOptions,
+) -> Result<Descriptor> {
+    options.notify(|o| o.metadata_serialized(buf.len() as u64));
+    let ((desc, ..), size, existing) =
+        oci.put_blob_deduplicated::<Noop, media_types::Inodes>(buf)?;
+    report.metadata_bytes += size;
+    report.count_blob(existing);
+    let verity_hash = get_fs_verity_digest(buf)?;
+    verity_data.insert(desc.digest.underlying(), verity_hash);
+    Ok(desc)
+}
+
+// writes the metadata of a layer, returning the blob which is referenced by the rootfs: either
+// the only blob with all the inodes or the index of the shards
+fn put_metadata(
+    oci: &Image,
+    inodes: &[Inode],
+    verity_data: &mut VerityData,
+    report: &mut BuildReport,
+    options: &BuildOptions,
+) -> Result<Descriptor> {
+    let max_shard_size = options
+        .max_metadata_shard_size
+        .unwrap_or(MAX_METADATA_SHARD_SIZE);
+    let shards = shard_inodes(inodes, max_shard_size)?;
+    if let [inodes] = shards[..] {
+        let buf = serialize_metadata(inodes)?;
+        return put_metadata_blob(oci, &buf, verity_data, report, options);
+    }
+
+    let shards = shards
+        .into_iter()
+        .map(|inodes| {
+            let buf = serialize_metadata(inodes)?;
+            let desc = put_metadata_blob(oci, &buf, verity_data, report, options)?;
+            Ok(MetadataShard {
+                blob: BlobRef {
+                    digest: desc.digest.underlying(),
+                    offset: 0,
+                    compressed: false,
+                },
+                // shards are never empty
+                first_ino: inodes[0].ino,
+                last_ino: inodes[inodes.len() - 1].ino,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let buf = serialize_shard_index(&shards)?;
+    put_metadata_blob(oci, &buf, verity_data, report, options)
+}
+
+// a chunk that was written to the image
+struct StoredChunk {
+    digest: [u8; SHA256_BLOCK_SIZE],
+    fs_verity_digest: [u8; SHA256_BLOCK_SIZE],
+    compressed: bool,
+    length: u64,
+    // the size of the blob, and whether it was already in the image
+    stored_size: u64,
+    existing: bool,
+}
+
+// compressing, hashing and writing the chunks is CPU bound, so it is done by a pool of workers
+// while the chunker keeps reading; the chunks are returned in the order of the stream, so the
+// resulting image doesn't depend on the scheduling of the workers
+fn put_chunks<C: Compression + Any>(
+    oci: &Image,
+    chunker: Chunks,
+    options: &BuildOptions,
+) -> Result<Vec<StoredChunk>> {
+    let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+    // bound the number of chunks waiting for a worker, so we don't read the whole stream in memory
+    // when the workers can't keep up
+    let (work_sender, work_receiver) = mpsc::sync_channel::<(usize, Vec<u8>)>(workers * 2);
+    // the workers own the receiver, so sending fails once all of them are gone
+    let work_receiver = Arc::new(Mutex::new(work_receiver));
+    let (result_sender, result_receiver) = mpsc::channel();
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        for _ in 0..workers {
+            let work_receiver = Arc::clone(&work_receiver);
+            let result_sender = result_sender.clone();
+            let failed = &failed;
+            s.spawn(move || loop {
+                // the lock guard is dropped as soon as a chunk is received
+                let job = work_receiver.lock().unwrap().recv();
+                let Ok((i, data)) = job else {
+                    break;
+                };
+                let stored = oci
+                    .put_blob_deduplicated::<C, media_types::Chunk>(&data)
+                    .map(
+                        |((desc, fs_verity_digest, compressed), stored_size, existing)| {
+                            options.notify(|o| o.chunk_stored(data.len() as u64, existing));
+                            StoredChunk {
+                                digest: desc.digest.underlying(),
+                                fs_verity_digest,
+                                compressed,
+                                length: data.len() as u64,
+                                stored_size,
+                                existing,
+                            }
+                        },
+                    );
+                // the chunker stops at its next chunk, the error is returned with the results
+                let stop = stored.is_err();
+                if stop {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                if result_sender.send((i, stored)).is_err() || stop {
+                    break;
+                }
+            });
+        }
+        drop(work_receiver);
+        drop(result_sender);
+
+        let mut chunker_error = None;
+        for (i, result) in chunker.enumerate() {
+            if failed.load(Ordering::Relaxed) {
+                break;
+            }
+            match result.and_then(|chunk| options.cancellation.check().map(|_| chunk)) {
+                Ok(chunk) => {
+                    options.notify(|o| o.bytes_chunked(chunk.len() as u64));
+                    if work_sender.send((i, chunk)).is_err() {
+                        // all the workers are gone, either because they failed or because they
+                        // panicked, which the scope propagates
+                        break;
+                    }
+                }
+                Err(e) => {
+                    chunker_error = Some(e);
+                    break;
+                }
+            }
+        }
+        // let the workers finish
+        drop(work_sender);
+        if let Some(e) = chunker_error {
+            return Err(e.into());
+        }
+
+        let mut results = result_receiver.iter().collect::<Vec<_>>();
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, stored)| stored).collect()
+    })
+}
+
+// lays out the chunks of the data of a sparse file at the ranges where the data is, with holes
+// in between
+fn with_holes(data: Vec<FileChunk>, ranges: &[(u64, u64)], len: u64) -> Vec<FileChunk> {
+    let mut chunks = Vec::new();
+    let mut data = data.into_iter();
+    let mut next = data.next();
+    let mut offset = 0;
+
+    for &(start, range_len) in ranges {
+        if start > offset {
+            chunks.push(FileChunk {
+                blob: None,
+                len: start - offset,
+            });
+        }
+
+        let mut remaining = range_len;
+        while remaining > 0 {
+            let Some(mut chunk) = next.take() else {
+                break;
+            };
+            if chunk.len <= remaining {
+                remaining -= chunk.len;
+                chunks.push(chunk);
+                next = data.next();
+            } else {
+                // the rest of the chunk is the data of the next range
+                chunks.push(FileChunk {
+                    blob: chunk.blob,
+                    len: remaining,
+                });
+                chunk.blob = chunk.blob.map(|blob| BlobRef {
+                    offset: blob.offset + remaining,
+                    ..blob
+                });
+                chunk.len -= remaining;
+                next = Some(chunk);
+                remaining = 0;
+            }
+        }
+        offset = start + range_len;
+    }
+
+    if len > offset {
+        chunks.push(FileChunk {
+            blob: None,
+            len: len - offset,
+        });
+    }
+    chunks
+}
+
 fn process_chunks<C: Compression + Any>(
     oci: &Image,
-    mut chunker: StreamCDC,
+    chunker: Chunks,
     files: &mut [File],
     verity_data: &mut VerityData,
+    report: &mut BuildReport,
+    options: &BuildOptions,
 ) -> Result<()> {
     let mut file_iter = files.iter_mut();
     let mut file_used = 0;
     let mut file = None;
     for f in file_iter.by_ref() {
-        if f.md.size() > 0 {
+        if f.size > 0 {
             file = Some(f);
             break;
         }
     }
 
-    'outer: for result in &mut chunker {
-        let chunk = result.unwrap();
-        let mut chunk_used: u64 = 0;
+    let chunks = put_chunks::<C>(oci, chunker, options)?;
+    for chunk in &chunks {
+        report.chunks += 1;
+        report.uncompressed_bytes += chunk.length;
+        report.compressed_bytes += chunk.stored_size;
+        report.count_blob(chunk.existing);
+    }
 
-        let (desc, fs_verity_digest, compressed) =
-            oci.put_blob::<C, media_types::Chunk>(&chunk.data)?;
+    let mut chunks = chunks.into_iter();
+    'outer: for chunk in &mut chunks {
+        let mut chunk_used: u64 = 0;
 
-        let verity_hash = fs_verity_digest;
-        verity_data.insert(desc.digest.underlying(), verity_hash);
+        verity_data.insert(chunk.digest, chunk.fs_verity_digest);
 
-        while chunk_used < chunk.length as u64 {
+        while chunk_used < chunk.length {
             let room = min(
-                file.as_ref().unwrap().md.len() - file_used,
-                chunk.length as u64 - chunk_used,
+                file.as_ref().unwrap().size - file_used,
+                chunk.length - chunk_used,
             );
 
             let blob = BlobRef {
                 offset: chunk_used,
-                digest: desc.digest.underlying(),
-                compressed,
+                digest: chunk.digest,
+                compressed: chunk.compressed,
             };
 
-            file.as_mut()
-                .unwrap()
-                .chunk_list
-                .chunks
-                .push(FileChunk { blob, len: room });
+            file.as_mut().unwrap().chunks.push(FileChunk {
+                blob: Some(blob),
+                len: room,
+            });
 
             chunk_used += room;
             file_used += room;
 
             // get next file
-            if file_used == file.as_ref().unwrap().md.len() {
+            if file_used == file.as_ref().unwrap().size {
                 file_used = 0;
                 file = None;
 
                 for f in file_iter.by_ref() {
-                    if f.md.size() > 0 {
+                    if f.size > 0 {
                         file = Some(f);
                         break;
                     }
@@ -173,25 +595,32 @@ fn process_chunks<C: Compression + Any>(
     }
 
     // If there are no files left we also expect there are no chunks left
-    assert!(chunker.next().is_none());
+    assert!(chunks.next().is_none());
 
     Ok(())
 }
 
 fn build_delta<C: Compression + Any>(
-    rootfs: &Path,
+    tree: &mut Tree,
     oci: &Image,
     mut existing: Option<PuzzleFS>,
     verity_data: &mut VerityData,
-) -> Result<Descriptor> {
-    let mut dirs = HashMap::<u64, Dir>::new();
+    chunk_params: ChunkParams,
+    canonicalization: Option<&Canonicalization>,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildReport)> {
+    chunk_params.validate()?;
     let mut files = Vec::<File>::new();
-    let mut others = Vec::<Other>::new();
     let mut pfs_inodes = Vec::<Inode>::new();
     let mut fs_stream = FilesystemStream::new();
+    // the inodes of entries that were deleted since the existing layers
+    let mut whiteouts = Vec::<Ino>::new();
+    // the directories that only list their changes, with the names they lost
+    let mut merged_dirs = Vec::new();
 
-    // host to puzzlefs inode mapping for hard link deteciton
-    let mut host_to_pfs = HashMap::<u64, Ino>::new();
+    // tree node to puzzlefs inode mapping for hard link detection
+    let mut node_to_pfs = HashMap::<NodeId, Ino>::new();
+    let links = tree.link_counts();
 
     let mut next_ino: u64 = existing
         .as_mut()
@@ -206,220 +635,245 @@ fn build_delta<C: Compression + Any>(
             .map(|o| o.flatten())
     }
 
-    let rootfs_dirs = walker(rootfs)
-        .into_iter()
-        .filter_entry(|de| de.metadata().map(|md| md.is_dir()).unwrap_or(true));
-
-    // we specially create the "/" InodeMode::Dir object, since we will not iterate over it as a
-    // child of some other directory
-    let root_metadata = fs::symlink_metadata(rootfs)?;
-    let root_additional = InodeAdditional::new(rootfs, &root_metadata)?;
-    dirs.insert(
-        root_metadata.ino(),
-        Dir {
-            ino: 1,
-            md: root_metadata,
-            dir_list: DirList {
-                entries: Vec::<DirEnt>::new(),
-                look_below: false,
-            },
-            additional: root_additional,
-        },
-    );
-
-    let rootfs_relative = |p: &Path| {
-        // .unwrap() here because we assume no programmer errors in this function (i.e. it is a
-        // puzzlefs bug here)
-        Path::new("/").join(p.strip_prefix(rootfs).unwrap())
-    };
-
-    for dir in rootfs_dirs {
-        let d = dir.map_err(io::Error::from)?;
-        let dir_path = rootfs_relative(d.path());
-        let existing_dirents: Vec<_> = lookup_existing(&mut existing, &dir_path)?
-            .and_then(|ex| -> Option<Vec<_>> {
-                if let InodeMode::Dir { dir_list } = ex.mode {
-                    Some(dir_list.entries)
-                } else {
-                    None
+    // visit the directories depth first, in the order of their names, so that inode numbers and
+    // the order of the files in the chunk stream are reproducible; the "/" directory is always
+    // inode #1
+    let root = lookup_existing(&mut existing, Path::new("/"))?;
+    let mut dirs = vec![(ROOT, PathBuf::from("/"), 1, root)];
+    node_to_pfs.insert(ROOT, 1);
+
+    while let Some((dir_node, dir_path, dir_ino, existing_dir)) = dirs.pop() {
+        options.cancellation.check()?;
+        let dir = tree.node(dir_node);
+        let existing_dirents = match &existing_dir {
+            Some(Inode {
+                mode: InodeMode::Dir { dir_list },
+                ..
+            }) => dir_list
+                .entries
+                .iter()
+                .map(|dir_ent| (dir_ent.name.clone(), dir_ent.ino))
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+        // a directory that keeps some of its entries from below only lists the ones that changed
+        // and looks below for the others; otherwise it is opaque and lists all of them
+        let look_below = existing_dirents
+            .keys()
+            .any(|name| dir.entries.contains_key(OsStr::from_bytes(name)));
+
+        // all the entries, to tell whether the directory changed, and the ones that did
+        let mut dir_entries = Vec::<DirEnt>::new();
+        let mut changed_entries = Vec::<DirEnt>::new();
+        let mut deleted_entries = Vec::new();
+        for (name, &ino) in &existing_dirents {
+            if !dir.entries.contains_key(OsStr::from_bytes(name)) {
+                whiteouts.push(ino);
+                if look_below {
+                    deleted_entries.push((name.clone(), ino));
                 }
-            })
-            .unwrap_or_default();
-
-        let mut new_dirents = fs::read_dir(d.path())?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
-        // sort the entries so we have reproducible puzzlefs images
-        new_dirents.sort_by_key(|a| a.file_name());
-
-        // add whiteout information
-        let this_metadata = fs::symlink_metadata(d.path())?;
-        let this_dir = dirs
-            .get_mut(&this_metadata.ino())
-            .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
-        for dir_ent in existing_dirents {
-            if !(new_dirents).iter().any(|new| {
-                new.path().file_name().unwrap_or_else(|| OsStr::new(""))
-                    == OsStr::from_bytes(&dir_ent.name)
-            }) {
-                pfs_inodes.push(Inode::new_whiteout(dir_ent.ino));
-                this_dir.add_entry(OsString::from_vec(dir_ent.name), dir_ent.ino);
             }
         }
 
-        for e in new_dirents {
-            let md = e.metadata()?;
+        let mut subdirs = Vec::new();
+        for (name, &child) in &dir.entries {
+            let child_path = dir_path.join(name);
+            let existing_inode = lookup_existing(&mut existing, &child_path)?;
 
-            let existing_inode = existing
-                .as_mut()
-                .map(|pfs| {
-                    let puzzlefs_path = rootfs_relative(&e.path());
-                    pfs.lookup(&puzzlefs_path)
-                })
-                .transpose()?
-                .flatten();
-
-            let cur_ino = existing_inode.map(|ex| ex.ino).unwrap_or_else(|| {
+            let cur_ino = existing_inode.as_ref().map(|ex| ex.ino).unwrap_or_else(|| {
                 let next = next_ino;
                 next_ino += 1;
                 next
             });
 
-            // now that we know the ino of this thing, let's put it in the parent directory (assuming
-            // this is not "/" for our image, aka inode #1)
-            if cur_ino != 1 {
-                // is this a hard link? if so, just use the existing ino we have rendered. otherewise,
-                // use a new one
-                let the_ino = host_to_pfs.get(&md.ino()).copied().unwrap_or(cur_ino);
-                let parent_path = e.path().parent().map(|p| p.to_path_buf()).ok_or_else(|| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("no parent for {}", e.path().display()),
-                    )
-                })?;
-                let parent = dirs
-                    .get_mut(&fs::symlink_metadata(parent_path)?.ino())
-                    .ok_or_else(|| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("no pfs inode for {}", e.path().display()),
-                        )
-                    })?;
-                parent.add_entry(
-                    e.path()
-                        .file_name()
-                        .unwrap_or_else(|| OsStr::new(""))
-                        .to_os_string(),
-                    the_ino,
-                );
+            // is this a hard link? if so, just use the existing ino we have rendered. otherwise,
+            // use a new one
+            let the_ino = node_to_pfs.get(&child).copied().unwrap_or(cur_ino);
+            let dir_ent = DirEnt {
+                name: OsString::into_vec(name.clone()),
+                ino: the_ino,
+            };
+            if existing_dirents.get(&dir_ent.name) != Some(&the_ino) {
+                changed_entries.push(dir_ent.clone());
+            }
+            dir_entries.push(dir_ent);
 
-                // if it was a hard link, we don't need to actually render it again
-                if host_to_pfs.contains_key(&md.ino()) {
-                    continue;
-                }
+            // if it was a hard link, we don't need to actually render it again
+            if node_to_pfs.contains_key(&child) {
+                continue;
             }
 
-            host_to_pfs.insert(md.ino(), cur_ino);
+            node_to_pfs.insert(child, cur_ino);
 
             // render as much of the inode as we can
-            // TODO: here are a bunch of optimizations we should do: no need to re-render things
-            // that are the same (whole inodes, metadata, etc.). For now we just re-render the
-            // whole metadata tree.
-            let additional = InodeAdditional::new(&e.path(), &md)?;
-
-            if md.is_dir() {
-                dirs.insert(
-                    md.ino(),
-                    Dir {
-                        ino: cur_ino,
-                        md,
-                        dir_list: DirList {
-                            entries: Vec::<DirEnt>::new(),
-                            look_below: false,
-                        },
-                        additional,
-                    },
-                );
-            } else if md.is_file() {
-                fs_stream.push(&e.path());
-
-                let file = File {
-                    ino: cur_ino,
-                    md,
-                    chunk_list: FileChunkList {
-                        chunks: Vec::<FileChunk>::new(),
-                    },
-                    additional,
-                };
+            let node = tree.node(child);
+            let owner = ownership::owner(options, canonicalization, node, &child_path);
+            if node.is_dir() {
+                subdirs.push((child, child_path, cur_ino, existing_inode));
+            } else if let Some(content) = &node.content {
+                let mode = InodeMode::File { chunks: Vec::new() };
+                let inode = render(node, cur_ino, links[&child], mode, owner, canonicalization);
+                let chunks = reusable_chunks(
+                    tree,
+                    child,
+                    &inode,
+                    existing_inode.as_ref(),
+                    existing.as_ref(),
+                    options,
+                )?;
+                if let Some(chunks) = chunks {
+                    let inode = Inode {
+                        mode: InodeMode::File { chunks },
+                        ..inode
+                    };
+                    push_if_changed(&mut pfs_inodes, inode, existing_inode.as_ref());
+                    continue;
+                }
+
+                let mut size = node.size;
+                let mut sparse = None;
+                match content {
+                    Content::Host {
+                        path,
+                        sparse: false,
+                    } => fs_stream.push(path),
+                    Content::Host { path, sparse: true } => {
+                        let ranges =
+                            filesystem::data_ranges(&std::fs::File::open(path)?, node.size)?;
+                        if ranges == [(0, node.size)] {
+                            fs_stream.push(path);
+                        } else {
+                            size = ranges.iter().map(|(_, len)| len).sum();
+                            fs_stream.push_data_ranges(path, ranges.clone());
+                            sparse = Some((ranges, node.size));
+                        }
+                    }
+                    Content::Spool { offset } => {
+                        // spooled content implies the spool exists
+                        let spool = tree.spool().unwrap();
+                        fs_stream.push_range(Arc::clone(spool), *offset, node.size)
+                    }
+                    Content::Image { .. } => unreachable!("the chunks of images are reused"),
+                }
 
-                files.push(file);
+                files.push(File {
+                    size,
+                    sparse,
+                    chunks: Vec::new(),
+                    existing: existing_inode,
+                    inode,
+                });
             } else {
-                let o = Other {
-                    ino: cur_ino,
-                    md,
-                    additional,
-                };
-                others.push(o);
+                let mode = node.inode.mode.clone();
+                let inode = render(node, cur_ino, links[&child], mode, owner, canonicalization);
+                push_if_changed(&mut pfs_inodes, inode, existing_inode.as_ref());
             }
         }
-    }
 
-    let fcdc = StreamCDC::new(
-        Box::new(fs_stream),
-        MIN_CHUNK_SIZE,
-        AVG_CHUNK_SIZE,
-        MAX_CHUNK_SIZE,
-    );
-    process_chunks::<C>(oci, fcdc, &mut files, verity_data)?;
+        // the existing directory is merged from all the layers, so it is compared with all the
+        // entries
+        let owner = ownership::owner(options, canonicalization, dir, &dir_path);
+        let mode = InodeMode::Dir {
+            dir_list: DirList {
+                entries: dir_entries,
+                look_below: false,
+            },
+        };
+        let inode = render(
+            dir,
+            dir_ino,
+            links[&dir_node],
+            mode,
+            owner,
+            canonicalization,
+        );
+        if is_changed(&inode, existing_dir.as_ref()) {
+            if look_below {
+                merged_dirs.push((inode, changed_entries, deleted_entries));
+            } else {
+                pfs_inodes.push(inode);
+            }
+        }
+        dirs.extend(subdirs.into_iter().rev());
+    }
 
-    // TODO: not render this whole thing in memory, stick it all in the same blob, etc.
-    let mut sorted_dirs = dirs.into_values().collect::<Vec<_>>();
+    // the inodes that lost all of their names are whited out, so that they can't be found by
+    // number either; the inode of a deleted hard link may still be used by another one of its names
+    let rendered_inos = node_to_pfs.values().collect::<HashSet<_>>();
+    let deleted_inos = whiteouts
+        .into_iter()
+        .filter(|ino| !rendered_inos.contains(ino))
+        .collect::<BTreeSet<_>>();
+    pfs_inodes.extend(deleted_inos.iter().map(|&ino| Inode::new_whiteout(ino)));
+
+    // the deleted names of the directories that look below point at whiteouts, which hide them
+    for (inode, mut entries, deleted) in merged_dirs {
+        for (name, ino) in deleted {
+            let ino = if deleted_inos.contains(&ino) {
+                ino
+            } else {
+                let whiteout = next_ino;
+                next_ino += 1;
+                pfs_inodes.push(Inode::new_whiteout(whiteout));
+                whiteout
+            };
+            entries.push(DirEnt { name, ino });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let dir_list = DirList {
+            entries,
+            look_below: true,
+        };
+        pfs_inodes.push(Inode {
+            mode: InodeMode::Dir { dir_list },
+            ..inode
+        });
+    }
 
-    // render dirs
-    pfs_inodes.extend(
-        sorted_dirs
-            .drain(..)
-            .map(|d| Ok(Inode::new_dir(d.ino, &d.md, d.dir_list, d.additional)?))
-            .collect::<Result<Vec<Inode>>>()?,
-    );
+    options.notify(|o| o.chunking_started(files.iter().map(|f| f.size).sum()));
+    let chunks = chunker::chunker(Box::new(fs_stream), chunk_params);
+    let mut report = BuildReport::default();
+    process_chunks::<C>(oci, chunks, &mut files, verity_data, &mut report, options)?;
 
     // render files
-    pfs_inodes.extend(
-        files
-            .drain(..)
-            .map(|f| {
-                Ok(Inode::new_file(
-                    f.ino,
-                    &f.md,
-                    f.chunk_list.chunks,
-                    f.additional,
-                )?)
-            })
-            .collect::<Result<Vec<Inode>>>()?,
-    );
-
-    pfs_inodes.extend(
-        others
-            .drain(..)
-            .map(|o| Ok(Inode::new_other(o.ino, &o.md, o.additional)?))
-            .collect::<Result<Vec<Inode>>>()?,
-    );
+    for f in files {
+        let chunks = match f.sparse {
+            Some((ranges, len)) => with_holes(f.chunks, &ranges, len),
+            None => f.chunks,
+        };
+        let inode = Inode {
+            mode: InodeMode::File { chunks },
+            ..f.inode
+        };
+        push_if_changed(&mut pfs_inodes, inode, f.existing.as_ref());
+    }
+    tree.set_rendered(node_to_pfs);
 
     pfs_inodes.sort_by(|a, b| a.ino.cmp(&b.ino));
-
-    let md_buf = serialize_metadata(pfs_inodes)?;
-
-    let (desc, ..) = oci.put_blob::<Noop, media_types::Inodes>(md_buf.as_slice())?;
-    let verity_hash = get_fs_verity_digest(md_buf.as_slice())?;
-    verity_data.insert(desc.digest.underlying(), verity_hash);
-
-    Ok(desc)
+    report.count_inodes(&pfs_inodes);
+    let desc = put_metadata(oci, &pfs_inodes, verity_data, &mut report, options)?;
+    options.notify(|o| o.layer_built(&report));
+    Ok((desc, report))
 }
 
-pub fn build_initial_rootfs<C: Compression + Any>(
-    rootfs: &Path,
+// renders the tree as the only layer of a new rootfs
+fn initial_rootfs<C: Compression + Any>(
+    tree: &mut Tree,
     oci: &Image,
-) -> Result<Descriptor> {
+    options: &BuildOptions,
+) -> Result<(Rootfs, BuildReport)> {
     let mut verity_data: VerityData = BTreeMap::new();
-    let desc = build_delta::<C>(rootfs, oci, None, &mut verity_data)?;
+    let chunk_params = options.chunk_params.unwrap_or_default();
+    let canonicalization = options.canonicalization.clone();
+    let (desc, report) = build_delta::<C>(
+        tree,
+        oci,
+        None,
+        &mut verity_data,
+        chunk_params,
+        canonicalization.as_ref(),
+        options,
+    )?;
     let metadatas = [BlobRef {
         offset: 0,
         digest: desc.digest.underlying(),
@@ -427,47 +881,163 @@ pub fn build_initial_rootfs<C: Compression + Any>(
     }]
     .to_vec();
 
-    let rootfs_buf = serialize_manifest(Rootfs {
+    let rootfs = Rootfs {
         metadatas,
         fs_verity_data: verity_data,
         manifest_version: PUZZLEFS_IMAGE_MANIFEST_VERSION,
-    })?;
+        chunk_params,
+        canonicalization,
+    };
+    Ok((rootfs, report))
+}
 
+// renders whatever the delta between the tree and the rootfs is as a new layer on top of it; unless
+// told otherwise, the new layer is chunked like the rootfs so that unchanged files share chunks
+fn delta_rootfs<C: Compression + Any>(
+    tree: &mut Tree,
+    oci: &Arc<Image>,
+    mut rootfs: Rootfs,
+    options: &BuildOptions,
+) -> Result<(Rootfs, BuildReport)> {
+    let mut verity_data: VerityData = BTreeMap::new();
+    let pfs = PuzzleFS::from_rootfs(Arc::clone(oci), &rootfs, None)?;
+    let chunk_params = options.chunk_params.unwrap_or(rootfs.chunk_params);
+    let canonicalization = options
+        .canonicalization
+        .clone()
+        .or(rootfs.canonicalization.take());
+
+    let (desc, report) = build_delta::<C>(
+        tree,
+        oci,
+        Some(pfs),
+        &mut verity_data,
+        chunk_params,
+        canonicalization.as_ref(),
+        options,
+    )?;
+    let br = BlobRef {
+        digest: desc.digest.underlying(),
+        offset: 0,
+        compressed: false,
+    };
+
+    if !rootfs.metadatas.iter().any(|&x| x == br) {
+        rootfs.metadatas.insert(0, br);
+    }
+
+    rootfs.fs_verity_data.extend(verity_data);
+    rootfs.chunk_params = chunk_params;
+    rootfs.canonicalization = canonicalization;
+    // the new layer may use features the rootfs didn't
+    rootfs.manifest_version = PUZZLEFS_IMAGE_MANIFEST_VERSION;
+    Ok((rootfs, report))
+}
+
+fn put_rootfs(oci: &Image, rootfs: Rootfs) -> Result<Descriptor> {
+    let rootfs_buf = serialize_manifest(rootfs)?;
     Ok(oci
         .put_blob::<Noop, media_types::Rootfs>(rootfs_buf.as_slice())?
         .0)
 }
 
+// renders the tree as a new image
+fn initial_tree<C: Compression + Any>(
+    tree: &mut Tree,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildReport)> {
+    let (rootfs, report) = initial_rootfs::<C>(tree, oci, options)?;
+    Ok((put_rootfs(oci, rootfs)?, report))
+}
+
+pub fn build_initial_rootfs<C: Compression + Any>(
+    rootfs: &Path,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<Descriptor> {
+    Ok(build_initial_rootfs_with_report::<C>(rootfs, oci, options)?.0)
+}
+
+/// Like [build_initial_rootfs], also returning what the layer is made of.
+pub fn build_initial_rootfs_with_report<C: Compression + Any>(
+    rootfs: &Path,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildReport)> {
+    let excludes = Excludes::new(Some(rootfs), &options.exclude)?;
+    let mut tree = Tree::from_dir(rootfs, &excludes, options)?;
+    initial_tree::<C>(&mut tree, oci, options)
+}
+
 // add_rootfs_delta adds whatever the delta between the current rootfs and the puzzlefs
 // representation from the tag is.
 pub fn add_rootfs_delta<C: Compression + Any>(
     rootfs_path: &Path,
     oci: Image,
     tag: &str,
+    options: &BuildOptions,
 ) -> Result<(Descriptor, Arc<Image>)> {
-    let mut verity_data: VerityData = BTreeMap::new();
-    let pfs = PuzzleFS::open(oci, tag, None)?;
-    let oci = Arc::clone(&pfs.oci);
-    let mut rootfs = oci.open_rootfs_blob::<Noop>(tag, None)?;
+    let (desc, oci, _) = add_rootfs_delta_with_report::<C>(rootfs_path, oci, tag, options)?;
+    Ok((desc, oci))
+}
 
-    let desc = build_delta::<C>(rootfs_path, &oci, Some(pfs), &mut verity_data)?;
-    let br = BlobRef {
-        digest: desc.digest.underlying(),
-        offset: 0,
-        compressed: false,
-    };
+/// Like [add_rootfs_delta], also returning what the new layer is made of.
+pub fn add_rootfs_delta_with_report<C: Compression + Any>(
+    rootfs_path: &Path,
+    oci: Image,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, BuildReport)> {
+    let excludes = Excludes::new(Some(rootfs_path), &options.exclude)?;
+    let mut tree = Tree::from_dir(rootfs_path, &excludes, options)?;
+    add_tree_delta::<C>(&mut tree, oci, tag, options)
+}
 
-    if !rootfs.metadatas.iter().any(|&x| x == br) {
-        rootfs.metadatas.insert(0, br);
-    }
+fn add_tree_delta<C: Compression + Any>(
+    tree: &mut Tree,
+    oci: Image,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, BuildReport)> {
+    let oci = Arc::new(oci);
+    let rootfs = oci.open_rootfs_blob::<Noop>(tag, None)?;
+    let (rootfs, report) = delta_rootfs::<C>(tree, &oci, rootfs, options)?;
+    Ok((put_rootfs(&oci, rootfs)?, oci, report))
+}
 
-    rootfs.fs_verity_data.extend(verity_data);
-    let rootfs_buf = serialize_manifest(rootfs)?;
-    Ok((
-        oci.put_blob::<Noop, media_types::Rootfs>(rootfs_buf.as_slice())?
-            .0,
-        oci,
-    ))
+// reads a root filesystem from an uncompressed tar archive; the contents of its regular files are
+// spooled to an anonymous temporary file, since they have to be chunked in the same order as for
+// a directory rather than in the order of the archive
+fn tree_from_tar(archive: impl Read, options: &BuildOptions) -> Result<Tree> {
+    let mut tree = Tree::new();
+    let excludes = Excludes::new(None, &options.exclude)?;
+    archive::apply_layer(&mut tree, archive, false, &excludes, options)?;
+    Ok(tree)
+}
+
+/// Builds the root filesystem stored in a tar archive, without unpacking it. The image is the same
+/// as the one built from the unpacked directory, as long as the archive preserves everything
+/// puzzlefs stores (e.g. nanosecond timestamps are only kept by pax archives). The report of the
+/// layer is returned with its descriptor.
+pub fn build_initial_rootfs_from_tar<C: Compression + Any>(
+    archive: impl Read,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildReport)> {
+    let mut tree = tree_from_tar(archive, options)?;
+    initial_tree::<C>(&mut tree, oci, options)
+}
+
+/// Like [add_rootfs_delta_with_report], with the root filesystem read from a tar archive.
+pub fn add_rootfs_delta_from_tar<C: Compression + Any>(
+    archive: impl Read,
+    oci: Image,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, BuildReport)> {
+    let mut tree = tree_from_tar(archive, options)?;
+    add_tree_delta::<C>(&mut tree, oci, tag, options)
 }
 
 pub fn enable_fs_verity(oci: Image, tag: &str, manifest_root_hash: &str) -> Result<()> {
@@ -514,7 +1084,7 @@ pub fn enable_fs_verity(oci: Image, tag: &str, manifest_root_hash: &str) -> Resu
 
 // TODO: figure out how to guard this with #[cfg(test)]
 pub fn build_test_fs(path: &Path, image: &Image) -> Result<Descriptor> {
-    build_initial_rootfs::<Zstd>(path, image)
+    build_initial_rootfs::<Zstd>(path, image, &BuildOptions::default())
 }
 
 #[cfg(test)]
@@ -522,15 +1092,44 @@ pub mod tests {
     use super::*;
 
     use std::backtrace::Backtrace;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::sync::atomic::{AtomicU64, Ordering};
 
     use tempfile::tempdir;
 
+    use crate::format::Timespec;
     use crate::reader::WalkPuzzleFS;
-    use std::path::PathBuf;
     use tempfile::TempDir;
+    use walkdir::WalkDir;
 
     type DefaultCompression = Zstd;
 
+    #[test]
+    fn test_with_holes() {
+        let blob = |offset| BlobRef {
+            digest: [0; 32],
+            offset,
+            compressed: false,
+        };
+        let chunk = |blob, len| FileChunk { blob, len };
+
+        // the second chunk is split by the hole between the two ranges of data
+        let data = vec![chunk(Some(blob(0)), 4), chunk(Some(blob(0)), 8)];
+        assert_eq!(
+            with_holes(data, &[(10, 6), (20, 6)], 30),
+            [
+                chunk(None, 10),
+                chunk(Some(blob(0)), 4),
+                chunk(Some(blob(0)), 2),
+                chunk(None, 4),
+                chunk(Some(blob(2)), 6),
+                chunk(None, 4),
+            ]
+        );
+        assert_eq!(with_holes(Vec::new(), &[], 10), [chunk(None, 10)]);
+    }
+
     #[test]
     fn test_fs_generation() -> anyhow::Result<()> {
         // TODO: verify the hash value here since it's only one thing? problem is as we change the
@@ -617,7 +1216,13 @@ pub mod tests {
         )
         .unwrap();
 
-        let (desc, image) = add_rootfs_delta::<DefaultCompression>(&delta_dir, image, tag).unwrap();
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &delta_dir,
+            image,
+            tag,
+            &BuildOptions::default(),
+        )
+        .unwrap();
         let new_tag = "test2";
         image.add_tag(new_tag, desc).unwrap();
         let delta = image.open_rootfs_blob::<Noop>(new_tag, None).unwrap();
@@ -646,6 +1251,864 @@ pub mod tests {
         assert!(walker.next().is_none());
     }
 
+    #[test]
+    fn test_multi_chunk_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir(&rootfs)?;
+
+        // enough incompressible data for many chunks, spanning file boundaries
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut contents = Vec::new();
+        for (i, len) in [3_000_000, 0, 1, 700_000].into_iter().enumerate() {
+            let data = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect::<Vec<u8>>();
+            fs::write(rootfs.join(format!("file{i}")), &data)?;
+            contents.push(data);
+        }
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let desc =
+            build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &BuildOptions::default())?;
+        image.add_tag("test", desc)?;
+
+        let mut pfs = PuzzleFS::open(image, "test", None)?;
+        let files = WalkPuzzleFS::walk(&mut pfs)?
+            .skip(1)
+            .map(|de| {
+                let mut data = Vec::new();
+                de?.open()?.read_to_end(&mut data)?;
+                Ok(data)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(files, contents);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tar_matches_directory() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("foo/bar"))?;
+        fs::create_dir(rootfs.join("empty"))?;
+        fs::write(rootfs.join("foo/bar/file"), b"some file contents")?;
+        fs::write(rootfs.join("zero"), b"")?;
+        fs::copy(
+            "src/builder/test/test-1/SekienAkashita.jpg",
+            rootfs.join("foo/SekienAkashita.jpg"),
+        )?;
+        std::os::unix::fs::symlink("foo/bar/file", rootfs.join("link"))?;
+
+        // ustar headers only have whole seconds
+        let mtime = nix::sys::time::TimeSpec::new(1_000_000_000, 0);
+        for entry in WalkDir::new(&rootfs) {
+            nix::sys::stat::utimensat(
+                None,
+                entry?.path(),
+                &mtime,
+                &mtime,
+                nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+            )?;
+        }
+
+        let mut archive = tar::Builder::new(Vec::new());
+        archive.follow_symlinks(false);
+        archive.append_dir_all(".", &rootfs)?;
+        let archive = archive.into_inner()?;
+
+        let dir_image = Image::new(&dir.path().join("dir-image"))?;
+        // tar archives don't have the atimes and ctimes of the files, clamping to the far future
+        // keeps the mtimes and derives the other timestamps from them for both images
+        let options = BuildOptions {
+            canonicalization: Some(Canonicalization {
+                timestamps: TimestampPolicy::Clamp(i64::MAX),
+                ..Canonicalization::default()
+            }),
+            ..BuildOptions::default()
+        };
+        let dir_desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &dir_image, &options)?;
+        let tar_image = Image::new(&dir.path().join("tar-image"))?;
+        let (tar_desc, _) = build_initial_rootfs_from_tar::<DefaultCompression>(
+            &archive[..],
+            &tar_image,
+            &options,
+        )?;
+
+        assert_eq!(dir_desc.digest, tar_desc.digest);
+        assert_eq!(get_image_blobs(&dir_image), get_image_blobs(&tar_image));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_params() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let chunk_params = ChunkParams {
+            algorithm: ChunkAlgorithm::Buzhash,
+            min_size: 4 * 1024,
+            avg_size: 16 * 1024,
+            max_size: 64 * 1024,
+        };
+        let options = BuildOptions {
+            chunk_params: Some(chunk_params),
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs::<DefaultCompression>(
+            Path::new("src/builder/test/test-1"),
+            &image,
+            &options,
+        )?;
+        image.add_tag("small-chunks", desc)?;
+        let rootfs = image.open_rootfs_blob::<Noop>("small-chunks", None)?;
+        assert_eq!(rootfs.chunk_params, chunk_params);
+        let blobs = get_image_blobs(&image);
+
+        // the delta reuses the chunking parameters of the base image, so the unchanged file is stored in
+        // the same chunks
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            Path::new("src/builder/test/test-1"),
+            image,
+            "small-chunks",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("delta", desc)?;
+        let delta = image.open_rootfs_blob::<Noop>("delta", None)?;
+        assert_eq!(delta.chunk_params, chunk_params);
+        let pfs = PuzzleFS::open(Image::open(dir.path())?, "delta", None)?;
+        let jpg = pfs.lookup(Path::new("/SekienAkashita.jpg"))?.unwrap();
+        let InodeMode::File { chunks } = jpg.mode else {
+            panic!("bad inode mode: {:?}", jpg.mode);
+        };
+        assert!(chunks.len() > 1);
+        for chunk in chunks {
+            let digest = OsString::from(Digest::new(&chunk.blob.unwrap().digest).to_string());
+            assert!(blobs.contains(&digest));
+        }
+
+        let invalid = BuildOptions {
+            chunk_params: Some(ChunkParams {
+                min_size: 64 * 1024,
+                avg_size: 16 * 1024,
+                max_size: 256 * 1024,
+                ..ChunkParams::default()
+            }),
+            ..BuildOptions::default()
+        };
+        assert!(build_initial_rootfs::<DefaultCompression>(
+            Path::new("src/builder/test/test-1"),
+            &Image::new(&dir.path().join("invalid"))?,
+            &invalid,
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sharded_metadata() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        for i in 0..20 {
+            let subdir = rootfs.join(format!("dir{i}"));
+            fs::create_dir_all(&subdir)?;
+            for j in 0..5 {
+                fs::write(subdir.join(format!("file{j}")), format!("contents {i} {j}"))?;
+            }
+        }
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let options = BuildOptions {
+            max_metadata_shard_size: Some(1024),
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &options)?;
+        image.add_tag("sharded", desc)?;
+
+        let manifest = image.open_rootfs_blob::<Noop>("sharded", None)?;
+        assert_eq!(manifest.metadatas.len(), 1);
+        let index = image.open_metadata_blob(&manifest.metadatas[0].try_into()?, None)?;
+        assert!(index.get_inode_vector()?.is_empty());
+        let shards = index.shards()?;
+        assert!(shards.len() > 1);
+        assert_eq!(shards[0].first_ino, 1);
+        for pair in shards.windows(2) {
+            assert_eq!(pair[0].last_ino + 1, pair[1].first_ino);
+        }
+
+        // a delta on top of a sharded layer only needs a single blob
+        fs::write(rootfs.join("dir7/file3"), b"changed")?;
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            image,
+            "sharded",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("delta", desc)?;
+
+        for tag in ["sharded", "delta"] {
+            let mut pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, tag, None)?;
+            assert_eq!(pfs.max_inode()?, 121);
+            assert!(pfs.find_inode(122).is_err());
+            let mut files = 0;
+            for entry in WalkPuzzleFS::walk(&mut pfs)? {
+                let entry = entry?;
+                if let InodeMode::File { .. } = entry.inode.mode {
+                    let mut data = Vec::new();
+                    entry.open()?.read_to_end(&mut data)?;
+                    let path = rootfs.join(entry.path.strip_prefix("/")?);
+                    let expected = match (tag, path.ends_with("dir7/file3")) {
+                        ("sharded", true) => b"contents 7 3".to_vec(),
+                        _ => fs::read(path)?,
+                    };
+                    assert_eq!(data, expected);
+                    files += 1;
+                }
+            }
+            assert_eq!(files, 100);
+        }
+
+        // the shards are only opened when an inode in their range is looked up
+        let last = shards.last().unwrap();
+        let digest = Digest::try_from(&last.blob)?;
+        fs::remove_file(image.blob_path().join(digest.to_string()))?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "sharded", None)?;
+        assert!(pfs.find_inode(1).is_ok());
+        assert!(pfs.find_inode(last.first_ino).is_err());
+        Ok(())
+    }
+
+    fn layer_inos(image: &Image, tag: &str) -> anyhow::Result<Vec<Ino>> {
+        let rootfs = image.open_rootfs_blob::<Noop>(tag, None)?;
+        let layer = image.open_metadata_blob(&rootfs.metadatas[0].try_into()?, None)?;
+        Ok(layer
+            .get_inode_vector()?
+            .iter()
+            .map(|i| i.get_ino())
+            .collect())
+    }
+
+    fn read_file(pfs: &PuzzleFS, path: &str) -> anyhow::Result<Vec<u8>> {
+        let inode = pfs.lookup(Path::new(path))?.unwrap();
+        let mut data = Vec::new();
+        FileReader::new(&pfs.oci, &inode)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_host_timestamps() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        let file = rootfs.join("file");
+        fs::write(&file, b"file")?;
+        nix::sys::stat::utimensat(
+            None,
+            &file,
+            &nix::sys::time::TimeSpec::new(500, 7),
+            &nix::sys::time::TimeSpec::new(1000, 5),
+            nix::sys::stat::UtimensatFlags::NoFollowSymlink,
+        )?;
+        let md = fs::metadata(&file)?;
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let options = BuildOptions::default();
+        let desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &options)?;
+        image.add_tag("base", desc)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "base", None)?;
+        let inode = pfs.lookup(Path::new("/file"))?.unwrap();
+        assert_eq!(inode.atime, Timespec::new(500, 7));
+        assert_eq!(inode.mtime, Timespec::new(1000, 5));
+        assert_eq!(inode.ctime, Timespec::new(md.ctime(), md.ctime_nsec()));
+
+        // building the image read the file, which bumped its atime, but the file didn't change
+        assert_ne!(fs::metadata(&file)?.atime(), 500);
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            Image::open(&dir.path().join("image"))?,
+            "base",
+            &options,
+        )?;
+        image.add_tag("delta", desc)?;
+        assert_eq!(layer_inos(&image, "delta")?, Vec::<Ino>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_incremental_delta() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc"))?;
+        fs::write(rootfs.join("etc/unchanged"), b"unchanged")?;
+        fs::write(rootfs.join("etc/changed"), b"before")?;
+        fs::write(rootfs.join("etc/sneaky"), b"before")?;
+        fs::copy(
+            "src/builder/test/test-1/SekienAkashita.jpg",
+            rootfs.join("SekienAkashita.jpg"),
+        )?;
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let options = BuildOptions::default();
+        let desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &options)?;
+        image.add_tag("base", desc)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "base", None)?;
+        let ino =
+            |path: &str| -> anyhow::Result<Ino> { Ok(pfs.lookup(Path::new(path))?.unwrap().ino) };
+        let blobs = get_image_blobs(&image).len();
+
+        // a change that keeps the size and the modification time can only be found by comparing
+        // the contents
+        let sneaky_mtime = fs::metadata(rootfs.join("etc/sneaky"))?.modified()?;
+        fs::write(rootfs.join("etc/sneaky"), b"after!")?;
+        fs::File::options()
+            .write(true)
+            .open(rootfs.join("etc/sneaky"))?
+            .set_modified(sneaky_mtime)?;
+        fs::write(rootfs.join("etc/changed"), b"after, longer")?;
+
+        let (desc, image) =
+            add_rootfs_delta::<DefaultCompression>(&rootfs, image, "base", &options)?;
+        image.add_tag("delta", desc)?;
+        // only the changed file is in the new layer, with a new chunk, besides the new metadata
+        // and manifest blobs
+        assert_eq!(layer_inos(&image, "delta")?, [ino("/etc/changed")?]);
+        assert_eq!(get_image_blobs(&image).len(), blobs + 3);
+
+        let verify = BuildOptions {
+            verify_unchanged_files: true,
+            ..BuildOptions::default()
+        };
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            Image::open(&dir.path().join("image"))?,
+            "base",
+            &verify,
+        )?;
+        image.add_tag("verified", desc)?;
+        let mut inos = [ino("/etc/changed")?, ino("/etc/sneaky")?];
+        inos.sort();
+        assert_eq!(layer_inos(&image, "verified")?, inos);
+
+        let delta = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "delta", None)?;
+        let verified = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "verified", None)?;
+        for pfs in [&delta, &verified] {
+            assert_eq!(read_file(pfs, "/etc/unchanged")?, b"unchanged");
+            assert_eq!(read_file(pfs, "/etc/changed")?, b"after, longer");
+            assert_eq!(
+                read_file(pfs, "/SekienAkashita.jpg")?,
+                fs::read(rootfs.join("SekienAkashita.jpg"))?
+            );
+        }
+        assert_eq!(read_file(&delta, "/etc/sneaky")?, b"before");
+        assert_eq!(read_file(&verified, "/etc/sneaky")?, b"after!");
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc"))?;
+        fs::create_dir_all(rootfs.join("cache/app"))?;
+        fs::write(rootfs.join(".puzzlefsignore"), "cache/\n*.log\n")?;
+        fs::write(rootfs.join("etc/passwd"), b"root")?;
+        fs::write(rootfs.join("etc/shadow"), b"secret")?;
+        fs::write(rootfs.join("cache/app/data"), b"data")?;
+        fs::write(rootfs.join("build.log"), b"log")?;
+        fs::write(rootfs.join("keep.log"), b"keep")?;
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let options = BuildOptions {
+            exclude: vec!["!keep.log".to_string()],
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &options)?;
+        image.add_tag("base", desc)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "base", None)?;
+        assert!(pfs.lookup(Path::new("/cache"))?.is_none());
+        assert!(pfs.lookup(Path::new("/build.log"))?.is_none());
+        assert_eq!(read_file(&pfs, "/keep.log")?, b"keep");
+        assert!(pfs.lookup(Path::new("/.puzzlefsignore"))?.is_some());
+        assert_eq!(pfs.lookup(Path::new("/"))?.unwrap().dir_entries()?.len(), 3);
+        let root = pfs.lookup(Path::new("/"))?.unwrap().ino;
+        let etc = pfs.lookup(Path::new("/etc"))?.unwrap().ino;
+        let shadow = pfs.lookup(Path::new("/etc/shadow"))?.unwrap().ino;
+
+        // excluding a path of the base layer hides it, excluding a new path leaves no trace
+        fs::write(rootfs.join("new.tmp"), b"tmp")?;
+        let options = BuildOptions {
+            exclude: vec![
+                "!keep.log".to_string(),
+                "shadow".to_string(),
+                "*.tmp".to_string(),
+            ],
+            ..BuildOptions::default()
+        };
+        let (desc, image) =
+            add_rootfs_delta::<DefaultCompression>(&rootfs, image, "base", &options)?;
+        image.add_tag("delta", desc)?;
+        // the root directory is only there because creating new.tmp changed its modification time
+        assert_eq!(layer_inos(&image, "delta")?, [root, etc, shadow]);
+
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "delta", None)?;
+        assert!(pfs.lookup(Path::new("/etc/shadow"))?.is_none());
+        assert!(pfs.lookup(Path::new("/new.tmp"))?.is_none());
+        assert_eq!(read_file(&pfs, "/etc/passwd")?, b"root");
+        Ok(())
+    }
+
+    #[test]
+    fn test_ownership() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc"))?;
+        fs::create_dir_all(rootfs.join("srv/app"))?;
+        fs::write(rootfs.join("etc/passwd"), b"root")?;
+        fs::write(rootfs.join("srv/app/bin"), b"bin")?;
+        let md = fs::metadata(&rootfs)?;
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let options = BuildOptions {
+            uid_map: IdMap::new(vec![IdMapping {
+                inside: 1000,
+                outside: md.uid(),
+                count: 1,
+            }])?,
+            gid_map: IdMap::new(vec![format!("2000 {} 1", md.gid()).parse().unwrap()])?,
+            force_owner: vec![
+                "/srv=3000:3000".parse().unwrap(),
+                "srv/app/bin=0:0".parse().unwrap(),
+            ],
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &options)?;
+        image.add_tag("test", desc)?;
+
+        let pfs = PuzzleFS::open(image, "test", None)?;
+        let owner = |path: &str| -> anyhow::Result<(u32, u32)> {
+            let inode = pfs.lookup(Path::new(path))?.unwrap();
+            Ok((inode.uid, inode.gid))
+        };
+        assert_eq!(owner("/")?, (1000, 2000));
+        assert_eq!(owner("/etc/passwd")?, (1000, 2000));
+        assert_eq!(owner("/srv")?, (3000, 3000));
+        assert_eq!(owner("/srv/app")?, (3000, 3000));
+        assert_eq!(owner("/srv/app/bin")?, (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "mounts a tmpfs, run with --ignored as root"]
+    fn test_cross_filesystems() -> anyhow::Result<()> {
+        use nix::mount::{mount, umount, MsFlags};
+
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        let cache = rootfs.join("var/cache");
+        fs::create_dir_all(&cache)?;
+        fs::write(rootfs.join("var/log"), b"log")?;
+        mount(
+            Some("tmpfs"),
+            &cache,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )?;
+        let built = (|| -> anyhow::Result<_> {
+            fs::create_dir(cache.join("sub"))?;
+            fs::write(cache.join("sub/file"), b"file")?;
+            fs::write(cache.join("top"), b"top")?;
+
+            let image = Image::new(&dir.path().join("image"))?;
+            let mut inodes = Vec::new();
+            for cross_filesystems in [false, true] {
+                let options = BuildOptions {
+                    cross_filesystems,
+                    ..BuildOptions::default()
+                };
+                let desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &options)?;
+                let tag = format!("cross-{cross_filesystems}");
+                image.add_tag(&tag, desc)?;
+                let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, &tag, None)?;
+                inodes.push(
+                    [
+                        "/var/log",
+                        "/var/cache",
+                        "/var/cache/top",
+                        "/var/cache/sub/file",
+                    ]
+                    .map(|path| pfs.lookup(Path::new(path)).unwrap().is_some()),
+                );
+            }
+            Ok(inodes)
+        })();
+        umount(&cache)?;
+
+        // the mount point is an empty directory, unless the build crosses filesystems
+        assert_eq!(built?, [[true, true, false, false], [true; 4]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_counts() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc/ssl"))?;
+        fs::create_dir_all(rootfs.join("usr"))?;
+        fs::write(rootfs.join("a"), b"a")?;
+        fs::hard_link(rootfs.join("a"), rootfs.join("etc/b"))?;
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let desc =
+            build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &BuildOptions::default())?;
+        image.add_tag("base", desc)?;
+        let pfs = PuzzleFS::open(image, "base", None)?;
+        let nlink = |pfs: &PuzzleFS, path: &str| -> anyhow::Result<u32> {
+            Ok(pfs.lookup(Path::new(path))?.unwrap().nlink)
+        };
+        assert_eq!(nlink(&pfs, "/")?, 4);
+        assert_eq!(nlink(&pfs, "/etc")?, 3);
+        assert_eq!(nlink(&pfs, "/etc/ssl")?, 2);
+        assert_eq!(nlink(&pfs, "/a")?, 2);
+        assert_eq!(nlink(&pfs, "/etc/b")?, 2);
+
+        // a new name for an existing file changes its link count, so the delta has its inode
+        fs::hard_link(rootfs.join("a"), rootfs.join("usr/c"))?;
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            Image::open(&dir.path().join("image"))?,
+            "base",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("delta", desc)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "delta", None)?;
+        let a = pfs.lookup(Path::new("/a"))?.unwrap();
+        assert_eq!(a.nlink, 3);
+        let mut inos = [a.ino, pfs.lookup(Path::new("/usr"))?.unwrap().ino];
+        inos.sort();
+        assert_eq!(layer_inos(&image, "delta")?, inos);
+        Ok(())
+    }
+
+    fn layer_dir(image: &Image, tag: &str, ino: Ino) -> anyhow::Result<DirList> {
+        let rootfs = image.open_rootfs_blob::<Noop>(tag, None)?;
+        let layer = image.open_metadata_blob(&rootfs.metadatas[0].try_into()?, None)?;
+        let inode = Inode::from_capnp(layer.find_inode(ino)?.unwrap())?;
+        let InodeMode::Dir { dir_list } = inode.mode else {
+            panic!("bad inode mode: {:?}", inode.mode);
+        };
+        Ok(dir_list)
+    }
+
+    #[test]
+    fn test_overlay_dirs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("etc"))?;
+        fs::create_dir_all(rootfs.join("var/cache"))?;
+        for name in ["etc/a", "etc/b", "etc/c", "var/cache/old"] {
+            fs::write(rootfs.join(name), name)?;
+        }
+        fs::hard_link(rootfs.join("etc/a"), rootfs.join("etc/link"))?;
+
+        let image = Image::new(&dir.path().join("image"))?;
+        let desc =
+            build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &BuildOptions::default())?;
+        image.add_tag("base", desc)?;
+        let base = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "base", None)?;
+        let ino =
+            |path: &str| -> anyhow::Result<Ino> { Ok(base.lookup(Path::new(path))?.unwrap().ino) };
+
+        fs::remove_file(rootfs.join("etc/b"))?;
+        fs::remove_file(rootfs.join("etc/link"))?;
+        fs::write(rootfs.join("etc/d"), b"d")?;
+        fs::remove_file(rootfs.join("var/cache/old"))?;
+        fs::write(rootfs.join("var/cache/new"), b"new")?;
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            Image::open(&dir.path().join("image"))?,
+            "base",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("delta", desc)?;
+
+        // etc only lists its changes: the new file and whiteouts for the deleted names; the link
+        // needs a whiteout of its own, since its inode is still etc/a
+        let etc = layer_dir(&image, "delta", ino("/etc")?)?;
+        assert!(etc.look_below);
+        let names = etc.entries.iter().map(|e| &e.name[..]).collect::<Vec<_>>();
+        assert_eq!(names, [&b"b"[..], b"d", b"link"]);
+        assert_eq!(etc.entries[0].ino, ino("/etc/b")?);
+        assert_ne!(etc.entries[2].ino, ino("/etc/a")?);
+        // nothing is left of var/cache below, so it hides the layers below
+        let cache = layer_dir(&image, "delta", ino("/var/cache")?)?;
+        assert!(!cache.look_below);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.entries[0].name, b"new");
+
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "delta", None)?;
+        let names = |path: &str| -> anyhow::Result<Vec<Vec<u8>>> {
+            let inode = pfs.lookup(Path::new(path))?.unwrap();
+            Ok(inode
+                .dir_entries()?
+                .iter()
+                .map(|e| e.name.clone())
+                .collect())
+        };
+        assert_eq!(names("/etc")?, [&b"a"[..], b"c", b"d"]);
+        assert_eq!(names("/var/cache")?, [b"new"]);
+        assert!(pfs.lookup(Path::new("/etc/b"))?.is_none());
+        assert!(pfs.lookup(Path::new("/etc/link"))?.is_none());
+        assert!(pfs.lookup(Path::new("/var/cache/old"))?.is_none());
+        assert!(pfs.find_inode(ino("/etc/b")?).is_err());
+        assert_eq!(read_file(&pfs, "/etc/a")?, b"etc/a");
+        assert_eq!(pfs.lookup(Path::new("/etc/a"))?.unwrap().nlink, 1);
+
+        // a delta on top of a delta merges all three layers
+        fs::write(rootfs.join("etc/b"), b"again")?;
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            Image::open(&dir.path().join("image"))?,
+            "delta",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("again", desc)?;
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("image"))?, "again", None)?;
+        let etc = pfs.lookup(Path::new("/etc"))?.unwrap();
+        let names = etc
+            .dir_entries()?
+            .iter()
+            .map(|e| &e.name[..])
+            .collect::<Vec<_>>();
+        assert_eq!(names, [&b"a"[..], b"b", b"c", b"d"]);
+        assert_eq!(read_file(&pfs, "/etc/b")?, b"again");
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Progress {
+        walked: AtomicU64,
+        total: AtomicU64,
+        chunked: AtomicU64,
+        stored: AtomicU64,
+        deduplicated: AtomicU64,
+        metadata: AtomicU64,
+        report: Mutex<Option<BuildReport>>,
+        cancel_after_chunk: Option<CancellationToken>,
+    }
+
+    impl BuildObserver for Progress {
+        fn file_walked(&self, _path: &Path) {
+            self.walked.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn chunking_started(&self, total: u64) {
+            self.total.fetch_add(total, Ordering::Relaxed);
+        }
+
+        fn bytes_chunked(&self, len: u64) {
+            self.chunked.fetch_add(len, Ordering::Relaxed);
+            if let Some(token) = &self.cancel_after_chunk {
+                token.cancel();
+            }
+        }
+
+        fn chunk_stored(&self, len: u64, deduplicated: bool) {
+            let counter = if deduplicated {
+                &self.deduplicated
+            } else {
+                &self.stored
+            };
+            counter.fetch_add(len, Ordering::Relaxed);
+        }
+
+        fn metadata_serialized(&self, len: u64) {
+            self.metadata.fetch_add(len, Ordering::Relaxed);
+        }
+
+        fn layer_built(&self, report: &BuildReport) {
+            *self.report.lock().unwrap() = Some(report.clone());
+        }
+    }
+
+    #[test]
+    fn test_progress() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        let size = fs::metadata(rootfs.join("SekienAkashita.jpg"))?.len();
+
+        let progress = Arc::new(Progress::default());
+        let options = BuildOptions {
+            observer: Some(progress.clone()),
+            ..BuildOptions::default()
+        };
+        let (_, report) =
+            build_initial_rootfs_with_report::<DefaultCompression>(rootfs, &image, &options)?;
+        assert_eq!(progress.walked.load(Ordering::Relaxed), 1);
+        assert_eq!(progress.total.load(Ordering::Relaxed), size);
+        assert_eq!(progress.chunked.load(Ordering::Relaxed), size);
+        assert_eq!(progress.stored.load(Ordering::Relaxed), size);
+        assert_eq!(progress.deduplicated.load(Ordering::Relaxed), 0);
+        assert!(progress.metadata.load(Ordering::Relaxed) > 0);
+        assert_eq!(progress.report.lock().unwrap().take(), Some(report.clone()));
+        assert_eq!(
+            (report.files, report.directories, report.other_inodes),
+            (1, 1, 0)
+        );
+        assert_eq!(report.logical_bytes, size);
+        assert_eq!(report.uncompressed_bytes, size);
+        assert!(report.compressed_bytes <= size);
+        assert!(report.chunks >= 1);
+        // the chunks and the metadata blob
+        assert_eq!(report.new_blobs, report.chunks + 1);
+        assert_eq!(report.existing_blobs, 0);
+        assert_eq!(
+            report.metadata_bytes,
+            progress.metadata.load(Ordering::Relaxed)
+        );
+
+        // building the same files again only finds chunks that are already in the image
+        let progress = Arc::new(Progress::default());
+        let options = BuildOptions {
+            observer: Some(progress.clone()),
+            ..BuildOptions::default()
+        };
+        let (_, report) =
+            build_initial_rootfs_with_report::<DefaultCompression>(rootfs, &image, &options)?;
+        assert_eq!(progress.stored.load(Ordering::Relaxed), 0);
+        assert_eq!(progress.deduplicated.load(Ordering::Relaxed), size);
+        assert_eq!(progress.report.lock().unwrap().take(), Some(report.clone()));
+        assert_eq!(report.new_blobs, 0);
+        assert_eq!(report.existing_blobs, report.chunks + 1);
+
+        let interrupted = |e: WireFormatError| matches!(e, WireFormatError::IOError(e, _) if e.kind() == io::ErrorKind::Interrupted);
+        let cancelled = BuildOptions::default();
+        cancelled.cancellation.cancel();
+        let err = build_initial_rootfs::<DefaultCompression>(rootfs, &image, &cancelled);
+        assert!(interrupted(err.unwrap_err()));
+
+        // the build stops between chunks
+        let cancellation = CancellationToken::new();
+        let progress = Arc::new(Progress {
+            cancel_after_chunk: Some(cancellation.clone()),
+            ..Progress::default()
+        });
+        let options = BuildOptions {
+            observer: Some(progress.clone()),
+            cancellation,
+            chunk_params: Some(ChunkParams {
+                algorithm: ChunkAlgorithm::Fixed,
+                min_size: 4096,
+                avg_size: 4096,
+                max_size: 4096,
+            }),
+            ..BuildOptions::default()
+        };
+        let err = build_initial_rootfs::<DefaultCompression>(rootfs, &image, &options);
+        assert!(interrupted(err.unwrap_err()));
+        assert_eq!(progress.chunked.load(Ordering::Relaxed), 4096);
+        assert_eq!(progress.metadata.load(Ordering::Relaxed), 0);
+        Ok(())
+    }
+
+    struct PanicOnChunk;
+
+    impl BuildObserver for PanicOnChunk {
+        fn chunk_stored(&self, _len: u64, _deduplicated: bool) {
+            panic!("chunk stored");
+        }
+    }
+
+    #[test]
+    fn test_chunk_worker_panic() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let rootfs = Path::new("src/builder/test/test-1");
+        // small chunks, so that there are more of them than the workers can take
+        let options = BuildOptions {
+            observer: Some(Arc::new(PanicOnChunk)),
+            chunk_params: Some(ChunkParams {
+                algorithm: ChunkAlgorithm::Fixed,
+                min_size: 512,
+                avg_size: 512,
+                max_size: 512,
+            }),
+            ..BuildOptions::default()
+        };
+        // the panic of the workers reaches the caller rather than leaving the chunker waiting
+        let built = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            build_initial_rootfs::<DefaultCompression>(rootfs, &image, &options)
+        }));
+        assert!(built.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonical_build() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let policy = Canonicalization {
+            dropped_xattrs: vec![b"user.label".to_vec()],
+            normalize_ownership: true,
+            normalize_permissions: true,
+            ..Canonicalization::default()
+        };
+        let options = BuildOptions {
+            canonicalization: Some(policy.clone()),
+            ..BuildOptions::default()
+        };
+
+        // the same files with different metadata give the same image
+        let mut digests = Vec::new();
+        for (name, file_mode, exe_mode) in [("a", 0o644, 0o755), ("b", 0o600, 0o750)] {
+            let rootfs = dir.path().join(name).join("rootfs");
+            fs::create_dir_all(rootfs.join("bin"))?;
+            fs::create_dir_all(rootfs.join("etc"))?;
+            fs::write(rootfs.join("etc/passwd"), b"root")?;
+            fs::write(rootfs.join("bin/sh"), b"sh")?;
+            fs::set_permissions(
+                rootfs.join("etc/passwd"),
+                fs::Permissions::from_mode(file_mode),
+            )?;
+            fs::set_permissions(rootfs.join("bin/sh"), fs::Permissions::from_mode(exe_mode))?;
+            xattr::set(rootfs.join("bin/sh"), "user.label", name.as_bytes())?;
+
+            let image = Image::new(&dir.path().join(name).join("image"))?;
+            let desc = build_initial_rootfs::<DefaultCompression>(&rootfs, &image, &options)?;
+            digests.push(desc.digest.underlying());
+            image.add_tag("base", desc)?;
+        }
+        assert_eq!(digests[0], digests[1]);
+
+        // all the modification times are the same, so the contents tell what changed
+        let rootfs = dir.path().join("b/rootfs");
+        fs::write(rootfs.join("etc/passwd"), b"toor")?;
+        let image = Image::open(&dir.path().join("b/image"))?;
+        let (desc, image) = add_rootfs_delta::<DefaultCompression>(
+            &rootfs,
+            image,
+            "base",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("delta", desc)?;
+        let rootfs = image.open_rootfs_blob::<Noop>("delta", None)?;
+        assert_eq!(rootfs.canonicalization, Some(policy));
+
+        let pfs = PuzzleFS::open(Image::open(&dir.path().join("b/image"))?, "delta", None)?;
+        assert_eq!(read_file(&pfs, "/etc/passwd")?, b"toor");
+        let passwd = pfs.lookup(Path::new("/etc/passwd"))?.unwrap();
+        assert_eq!(passwd.permissions, 0o644);
+        assert_eq!(passwd.mtime, Timespec::default());
+        let sh = pfs.lookup(Path::new("/bin/sh"))?.unwrap();
+        assert_eq!((sh.uid, sh.gid, sh.permissions), (0, 0, 0o755));
+        assert_eq!(sh.additional, None);
+        Ok(())
+    }
+
     fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
         if a.len() != b.len() {
             return false;
@@ -667,6 +2130,16 @@ pub mod tests {
             .collect::<Vec<OsString>>()
     }
 
+    // the atimes and ctimes of the files differ between copies of a directory, and building an
+    // image reads the files, which bumps their atimes, so only canonical builds are reproducible
+    fn build_canonical_fs(path: &Path, image: &Image) -> Result<Descriptor> {
+        let options = BuildOptions {
+            canonicalization: Some(Canonicalization::default()),
+            ..BuildOptions::default()
+        };
+        build_initial_rootfs::<Zstd>(path, image, &options)
+    }
+
     // given the same directory, test whether building it multiple times results in the same puzzlefs image
     fn same_dir_reproducible(path: &Path) -> bool {
         let dirs: [_; 10] = std::array::from_fn(|_| tempdir().unwrap());
@@ -677,7 +2150,7 @@ pub mod tests {
             .collect::<Vec<Image>>();
 
         for (i, image) in images.iter().enumerate() {
-            build_test_fs(path, image).unwrap();
+            build_canonical_fs(path, image).unwrap();
             let ents = get_image_blobs(image);
             sha_suite.push(ents);
 
@@ -700,7 +2173,7 @@ pub mod tests {
             .collect::<Vec<Image>>();
 
         for (i, image) in images.iter().enumerate() {
-            build_test_fs(&path[i], image).unwrap();
+            build_canonical_fs(&path[i], image).unwrap();
             let ents = get_image_blobs(image);
             sha_suite.push(ents);
 
@@ -0,0 +1,454 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use tar::{Archive, Entry, EntryType};
+
+use super::exclude::Excludes;
+use super::tree::{components, default_dir, empty_dir_list, Node, NodeId, Tree, ROOT};
+use super::BuildOptions;
+use crate::format::{Inode, InodeAdditional, InodeMode, Result, Timespec, Xattr};
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
+const PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// applies a tar archive on top of tree: each entry replaces whatever was at its path (except for
+// directories, which are merged with the existing ones). For OCI image layers, whiteout files hide
+// entries of the lower layers instead of being added to the tree, see
+// https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts. Excluded entries are
+// pruned once the whole archive is applied, so that the other names of a hard linked file keep it.
+pub(crate) fn apply_layer<R: Read>(
+    tree: &mut Tree,
+    layer: R,
+    whiteouts: bool,
+    excludes: &Excludes,
+    options: &BuildOptions,
+) -> Result<()> {
+    let mut archive = Archive::new(layer);
+    // entries created by this layer, which must survive an opaque whiteout of their directory
+    let mut added = HashSet::<(NodeId, OsString)>::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        options.cancellation.check()?;
+        options.notify(|o| o.file_walked(&path));
+        let mut names = components(&path)?;
+        let Some(name) = names.pop() else {
+            // an entry for the root directory itself, e.g. "./"
+            if entry.header().entry_type().is_dir() {
+                tree.node_mut(ROOT).inode = entry_inode(&mut entry, dir_mode(), None)?;
+            }
+            continue;
+        };
+
+        if whiteouts && name.as_bytes() == OPAQUE_WHITEOUT {
+            if let Some(dir) = lookup_dir(tree, &names) {
+                let lower = tree
+                    .node(dir)
+                    .entries
+                    .keys()
+                    .filter(|name| !added.contains(&(dir, (*name).clone())))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for name in lower {
+                    tree.unlink(dir, &name);
+                }
+            }
+            continue;
+        }
+
+        if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX) {
+            if whiteouts {
+                if let Some(dir) = lookup_dir(tree, &names) {
+                    tree.unlink(dir, OsStr::from_bytes(hidden));
+                }
+                continue;
+            }
+        }
+
+        let parent = mkdir_all(tree, &names, &mut added)?;
+        let node = match entry.header().entry_type() {
+            EntryType::Directory => {
+                let inode = entry_inode(&mut entry, dir_mode(), None)?;
+                match tree.node(parent).entries.get(&name) {
+                    Some(&existing) if tree.node(existing).is_dir() => {
+                        tree.node_mut(existing).inode = inode;
+                        existing
+                    }
+                    _ => tree.add_node(Node::new(inode)),
+                }
+            }
+            EntryType::Regular | EntryType::Continuous => {
+                let mode = InodeMode::File { chunks: Vec::new() };
+                let inode = entry_inode(&mut entry, mode, None)?;
+                let (content, size) = tree.spool_content(&mut entry)?;
+                tree.add_node(Node::new_file(inode, content, size))
+            }
+            EntryType::Link => {
+                let target = link_name(&entry)?;
+                tree.lookup(Path::new(&target))?.ok_or_else(|| {
+                    invalid_data(format!(
+                        "hard link target {} of {} not found",
+                        Path::new(&target).display(),
+                        path.display()
+                    ))
+                })?
+            }
+            EntryType::Symlink => {
+                let target = link_name(&entry)?;
+                let inode = entry_inode(&mut entry, InodeMode::Lnk, Some(target.into_vec()))?;
+                tree.add_node(Node::new(inode))
+            }
+            EntryType::Char | EntryType::Block => {
+                let header = entry.header();
+                let major = header.device_major()?.unwrap_or(0).into();
+                let minor = header.device_minor()?.unwrap_or(0).into();
+                let mode = if header.entry_type() == EntryType::Char {
+                    InodeMode::Chr { major, minor }
+                } else {
+                    InodeMode::Blk { major, minor }
+                };
+                let inode = entry_inode(&mut entry, mode, None)?;
+                tree.add_node(Node::new(inode))
+            }
+            EntryType::Fifo => {
+                let inode = entry_inode(&mut entry, InodeMode::Fifo, None)?;
+                tree.add_node(Node::new(inode))
+            }
+            entry_type => {
+                warn!(
+                    "skipping {} with unsupported type {:?}",
+                    path.display(),
+                    entry_type
+                );
+                continue;
+            }
+        };
+
+        tree.link(parent, &name, node);
+        added.insert((parent, name));
+    }
+
+    tree.prune(excludes);
+    Ok(())
+}
+
+fn dir_mode() -> InodeMode {
+    InodeMode::Dir {
+        dir_list: empty_dir_list(),
+    }
+}
+
+fn lookup_dir(tree: &Tree, names: &[OsString]) -> Option<NodeId> {
+    let mut cur = ROOT;
+    for name in names {
+        cur = *tree.node(cur).entries.get(name)?;
+    }
+    tree.node(cur).is_dir().then_some(cur)
+}
+
+// returns the directory with the given path, creating it and any missing parents
+pub(crate) fn mkdir_all(
+    tree: &mut Tree,
+    names: &[OsString],
+    added: &mut HashSet<(NodeId, OsString)>,
+) -> io::Result<NodeId> {
+    let mut cur = ROOT;
+    for name in names {
+        cur = match tree.node(cur).entries.get(name) {
+            Some(&next) if tree.node(next).is_dir() => next,
+            Some(_) => {
+                return Err(invalid_data(format!(
+                    "{} is not a directory",
+                    names.iter().collect::<PathBuf>().display()
+                )))
+            }
+            None => {
+                let next = tree.add_node(Node::new(default_dir()));
+                tree.link(cur, name, next);
+                added.insert((cur, name.clone()));
+                next
+            }
+        }
+    }
+    Ok(cur)
+}
+
+fn link_name<R: Read>(entry: &Entry<'_, R>) -> io::Result<OsString> {
+    entry
+        .link_name()?
+        .map(|target| target.into_owned().into_os_string())
+        .ok_or_else(|| {
+            invalid_data(format!(
+                "no link name for {}",
+                entry.path_bytes().escape_ascii()
+            ))
+        })
+}
+
+fn entry_inode<R: Read>(
+    entry: &mut Entry<'_, R>,
+    mode: InodeMode,
+    symlink_target: Option<Vec<u8>>,
+) -> Result<Inode> {
+    let header = entry.header();
+    let mut uid = header.uid()?;
+    let mut gid = header.gid()?;
+    // only preserve rwx permissions for user, group, others (9 bits) and SUID/SGID/sticky bit (3 bits)
+    let permissions = (header.mode()? & 0o7777) as u16;
+    let mut mtime = Timespec::new(header.mtime()?.try_into()?, 0);
+    // ustar only has the mtime, the other timestamps default to it
+    let mut atime = None;
+    let mut ctime = None;
+    let mut xattrs = Vec::new();
+
+    // pax extended headers override the ustar fields and carry the fields ustar doesn't have
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let value = || {
+                extension
+                    .value()
+                    .map_err(|e| invalid_data(format!("invalid pax value: {e}")))
+            };
+            match extension.key_bytes() {
+                b"uid" => uid = parse_pax_number(value()?)?,
+                b"gid" => gid = parse_pax_number(value()?)?,
+                b"mtime" => mtime = parse_pax_time(value()?)?,
+                b"atime" => atime = Some(parse_pax_time(value()?)?),
+                b"ctime" => ctime = Some(parse_pax_time(value()?)?),
+                key => {
+                    if let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX) {
+                        xattrs.push(Xattr {
+                            key: name.to_vec(),
+                            val: extension.value_bytes().to_vec(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    let additional = if symlink_target.is_none() && xattrs.is_empty() {
+        None
+    } else {
+        Some(InodeAdditional {
+            xattrs,
+            symlink_target,
+        })
+    };
+
+    Ok(Inode {
+        ino: 0,
+        mode,
+        uid: uid.try_into()?,
+        gid: gid.try_into()?,
+        permissions,
+        atime: atime.unwrap_or(mtime),
+        mtime,
+        ctime: ctime.unwrap_or(mtime),
+        nlink: 1,
+        additional,
+    })
+}
+
+fn parse_pax_number(value: &str) -> io::Result<u64> {
+    value
+        .parse()
+        .map_err(|e| invalid_data(format!("invalid pax number {value}: {e}")))
+}
+
+// pax timestamps are decimal seconds since the epoch, with an optional fractional part
+fn parse_pax_time(value: &str) -> io::Result<Timespec> {
+    let invalid = |e| invalid_data(format!("invalid pax timestamp {value}: {e}"));
+    let (sec, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let sec = sec.parse::<i64>().map_err(invalid)?;
+    let digits = &fraction[..fraction.len().min(9)];
+    let mut nsec = 0;
+    if !digits.is_empty() {
+        nsec = digits.parse::<i64>().map_err(invalid)? * 10_i64.pow(9 - digits.len() as u32);
+    }
+    // the fraction has the same sign as the seconds, e.g. -1.5 is 1.5 seconds before the epoch
+    if value.starts_with('-') && nsec > 0 {
+        Ok(Timespec::new(sec - 1, 1_000_000_000 - nsec))
+    } else {
+        Ok(Timespec::new(sec, nsec))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tar::{Builder, Header};
+
+    fn append(builder: &mut Builder<Vec<u8>>, path: &str, entry_type: EntryType, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn names(tree: &Tree, path: &str) -> Vec<OsString> {
+        let dir = tree.lookup(Path::new(path)).unwrap().unwrap();
+        tree.node(dir).entries.keys().cloned().collect()
+    }
+
+    #[test]
+    fn test_whiteouts() -> anyhow::Result<()> {
+        let mut lower = Builder::new(Vec::new());
+        append(&mut lower, "etc/", EntryType::Directory, b"");
+        append(&mut lower, "etc/passwd", EntryType::Regular, b"root");
+        append(&mut lower, "etc/shadow", EntryType::Regular, b"secret");
+        append(&mut lower, "var/cache/a", EntryType::Regular, b"a");
+        append(&mut lower, "var/cache/b", EntryType::Regular, b"b");
+
+        let mut upper = Builder::new(Vec::new());
+        append(&mut upper, "etc/.wh.shadow", EntryType::Regular, b"");
+        append(&mut upper, "var/cache/c", EntryType::Regular, b"c");
+        append(
+            &mut upper,
+            "var/cache/.wh..wh..opq",
+            EntryType::Regular,
+            b"",
+        );
+        append(&mut upper, "var/log/d", EntryType::Regular, b"d");
+
+        let excludes = Excludes::new(None, &[])?;
+        let options = BuildOptions::default();
+        let mut tree = Tree::new();
+        apply_layer(
+            &mut tree,
+            &lower.into_inner()?[..],
+            true,
+            &excludes,
+            &options,
+        )?;
+        apply_layer(
+            &mut tree,
+            &upper.into_inner()?[..],
+            true,
+            &excludes,
+            &options,
+        )?;
+
+        assert_eq!(names(&tree, "/"), ["etc", "var"]);
+        assert_eq!(names(&tree, "etc"), ["passwd"]);
+        assert_eq!(names(&tree, "var"), ["cache", "log"]);
+        assert_eq!(names(&tree, "var/cache"), ["c"]);
+        assert_eq!(names(&tree, "var/log"), ["d"]);
+
+        let passwd = tree.node(tree.lookup(Path::new("etc/passwd"))?.unwrap());
+        assert_eq!(passwd.size, 4);
+        assert_eq!(passwd.inode.permissions, 0o644);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_excludes() -> anyhow::Result<()> {
+        let mut layer = Builder::new(Vec::new());
+        append(&mut layer, "./etc/passwd", EntryType::Regular, b"root");
+        append(&mut layer, "./var/", EntryType::Directory, b"");
+        append(&mut layer, "./var/cache/", EntryType::Directory, b"");
+        append(&mut layer, "./var/cache/a", EntryType::Regular, b"a");
+        append(&mut layer, "./var/log/b.log", EntryType::Regular, b"b");
+        append(&mut layer, "./lib/libfoo.so.1", EntryType::Regular, b"foo");
+        // a hard link to an excluded file keeps it under its own name
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        layer.append_link(&mut header, "./lib/libfoo.so", "./lib/libfoo.so.1")?;
+
+        let excludes = Excludes::new(
+            None,
+            &[
+                "cache/".to_string(),
+                "*.log".to_string(),
+                "*.so.1".to_string(),
+            ],
+        )?;
+        let options = BuildOptions::default();
+        let mut tree = Tree::new();
+        apply_layer(
+            &mut tree,
+            &layer.into_inner()?[..],
+            false,
+            &excludes,
+            &options,
+        )?;
+
+        assert_eq!(names(&tree, "/"), ["etc", "lib", "var"]);
+        assert_eq!(names(&tree, "var"), ["log"]);
+        assert_eq!(names(&tree, "var/log"), Vec::<OsString>::new());
+        assert_eq!(names(&tree, "lib"), ["libfoo.so"]);
+        let libfoo = tree.lookup(Path::new("lib/libfoo.so"))?.unwrap();
+        assert_eq!(tree.node(libfoo).size, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_timestamps() -> anyhow::Result<()> {
+        let mut archive = Builder::new(Vec::new());
+        let records = b"14 atime=12.5\n12 ctime=34\n15 mtime=56.25\n";
+        append(&mut archive, "pax", EntryType::XHeader, records);
+        append(&mut archive, "with-pax", EntryType::Regular, b"");
+        append(&mut archive, "without-pax", EntryType::Regular, b"");
+
+        let excludes = Excludes::new(None, &[])?;
+        let mut tree = Tree::new();
+        let options = BuildOptions::default();
+        apply_layer(
+            &mut tree,
+            &archive.into_inner()?[..],
+            true,
+            &excludes,
+            &options,
+        )?;
+
+        let inode = |path: &str| {
+            let node = tree.lookup(Path::new(path)).unwrap().unwrap();
+            tree.node(node).inode.clone()
+        };
+        let with_pax = inode("with-pax");
+        assert_eq!(with_pax.atime, Timespec::new(12, 500_000_000));
+        assert_eq!(with_pax.ctime, Timespec::new(34, 0));
+        assert_eq!(with_pax.mtime, Timespec::new(56, 250_000_000));
+        // the records only apply to the next entry, and ustar only has the mtime
+        let without_pax = inode("without-pax");
+        assert_eq!(without_pax.atime, Timespec::new(0, 0));
+        assert_eq!(without_pax.ctime, Timespec::new(0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_time() {
+        assert_eq!(parse_pax_time("12").unwrap(), Timespec::new(12, 0));
+        assert_eq!(
+            parse_pax_time("12.5").unwrap(),
+            Timespec::new(12, 500_000_000)
+        );
+        assert_eq!(
+            parse_pax_time("12.1234567891").unwrap(),
+            Timespec::new(12, 123_456_789)
+        );
+        assert_eq!(
+            parse_pax_time("-1.5").unwrap(),
+            Timespec::new(-2, 500_000_000)
+        );
+        assert!(parse_pax_time("12.x").is_err());
+    }
+}
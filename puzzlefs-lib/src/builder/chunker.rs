@@ -0,0 +1,215 @@
+use std::io::{self, BufRead, BufReader, Read};
+
+use fastcdc::v2020::StreamCDC;
+
+use crate::format::{ChunkAlgorithm, ChunkParams};
+
+// the chunks of a stream, in order
+pub(crate) type Chunks = Box<dyn Iterator<Item = io::Result<Vec<u8>>>>;
+
+pub(crate) fn chunker(reader: Box<dyn Read>, params: ChunkParams) -> Chunks {
+    match params.algorithm {
+        ChunkAlgorithm::FastCdc => Box::new(
+            StreamCDC::new(reader, params.min_size, params.avg_size, params.max_size).map(
+                |result| match result {
+                    Ok(chunk) => Ok(chunk.data),
+                    Err(fastcdc::v2020::Error::IoError(e)) => Err(e),
+                    Err(e) => Err(io::Error::other(e)),
+                },
+            ),
+        ),
+        ChunkAlgorithm::Fixed => Box::new(FixedChunker {
+            reader,
+            size: params.avg_size,
+        }),
+        ChunkAlgorithm::Buzhash => Box::new(BuzhashChunker::new(reader, params)),
+    }
+}
+
+struct FixedChunker {
+    reader: Box<dyn Read>,
+    size: u32,
+}
+
+impl Iterator for FixedChunker {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = Vec::new();
+        match self
+            .reader
+            .by_ref()
+            .take(self.size.into())
+            .read_to_end(&mut chunk)
+        {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(chunk)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// the number of bytes the rolling hash is computed over, same as casync
+const BUZHASH_WINDOW: usize = 48;
+
+// the hash of each byte value; this table defines where the chunk boundaries are, so it must never
+// change, otherwise images built with different versions wouldn't share chunks anymore
+const BUZHASH_TABLE: [u32; 256] = {
+    // splitmix64
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = ((z ^ (z >> 31)) >> 32) as u32;
+        i += 1;
+    }
+    table
+};
+
+// content defined chunking in the style of casync: a chunk ends where the buzhash of the last
+// BUZHASH_WINDOW bytes hits the discriminator, as long as it is between the minimum and maximum size
+struct BuzhashChunker {
+    reader: BufReader<Box<dyn Read>>,
+    min_size: usize,
+    max_size: usize,
+    discriminator: u32,
+}
+
+impl BuzhashChunker {
+    fn new(reader: Box<dyn Read>, params: ChunkParams) -> Self {
+        // casync's approximation of the discriminator which makes the average chunk size close
+        // to the requested one, given the minimum and maximum sizes are enforced
+        let avg_size = f64::from(params.avg_size);
+        let discriminator = (avg_size / (-1.42888852e-7 * avg_size + 1.33237515)) as u32;
+        BuzhashChunker {
+            reader: BufReader::new(reader),
+            min_size: params.min_size as usize,
+            max_size: params.max_size as usize,
+            discriminator: discriminator.max(1),
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = Vec::new();
+        let mut hash: u32 = 0;
+
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(chunk);
+            }
+
+            let mut used = 0;
+            let mut boundary = false;
+            for &byte in buf {
+                used += 1;
+                chunk.push(byte);
+                hash = hash.rotate_left(1) ^ BUZHASH_TABLE[usize::from(byte)];
+                if chunk.len() > BUZHASH_WINDOW {
+                    let out = chunk[chunk.len() - 1 - BUZHASH_WINDOW];
+                    hash ^= BUZHASH_TABLE[usize::from(out)].rotate_left(BUZHASH_WINDOW as u32);
+                }
+
+                if chunk.len() >= self.max_size
+                    || (chunk.len() >= self.min_size
+                        && hash % self.discriminator == self.discriminator - 1)
+                {
+                    boundary = true;
+                    break;
+                }
+            }
+
+            self.reader.consume(used);
+            if boundary {
+                return Ok(chunk);
+            }
+        }
+    }
+}
+
+impl Iterator for BuzhashChunker {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_chunk() {
+            Ok(chunk) if chunk.is_empty() => None,
+            result => Some(result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    fn random_data(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks(data: &[u8], params: ChunkParams) -> Vec<Vec<u8>> {
+        chunker(Box::new(io::Cursor::new(data.to_vec())), params)
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_chunkers() {
+        let data = random_data(2_000_000, 0x2545_f491_4f6c_dd1d);
+        for algorithm in [
+            ChunkAlgorithm::FastCdc,
+            ChunkAlgorithm::Fixed,
+            ChunkAlgorithm::Buzhash,
+        ] {
+            let params = ChunkParams {
+                algorithm,
+                ..ChunkParams::default()
+            };
+            let chunks = chunks(&data, params);
+            assert_eq!(chunks.concat(), data, "{algorithm}");
+            for chunk in &chunks[..chunks.len() - 1] {
+                if algorithm == ChunkAlgorithm::Fixed {
+                    assert_eq!(chunk.len(), params.avg_size as usize);
+                } else {
+                    assert!(chunk.len() >= params.min_size as usize, "{algorithm}");
+                    assert!(chunk.len() <= params.max_size as usize, "{algorithm}");
+                }
+            }
+            assert!(super::chunker(Box::new(io::empty()), params)
+                .next()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_buzhash_is_content_defined() {
+        let params = ChunkParams {
+            algorithm: ChunkAlgorithm::Buzhash,
+            ..ChunkParams::default()
+        };
+        let data = random_data(2_000_000, 0x9e37_79b9_7f4a_7c15);
+        let mut shifted = b"a few more bytes at the start".to_vec();
+        shifted.extend_from_slice(&data);
+
+        let original = chunks(&data, params);
+        let shifted = chunks(&shifted, params);
+        assert!(original.len() > 10);
+
+        // only the first chunk is different
+        let original = original.into_iter().collect::<HashSet<_>>();
+        let shared = shifted.iter().filter(|c| original.contains(*c)).count();
+        assert!(shared >= original.len() - 2);
+    }
+}
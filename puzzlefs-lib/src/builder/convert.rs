@@ -0,0 +1,258 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+
+use super::archive::apply_layer;
+use super::exclude::Excludes;
+use super::tree::Tree;
+use super::{delta_rootfs, initial_rootfs, put_rootfs, BuildOptions, BuildReport};
+use crate::compression::Compression;
+use crate::format::Result;
+use crate::oci::{Descriptor, Image};
+
+// the parts of the OCI image layout (https://github.com/opencontainers/image-spec/blob/main/image-layout.md)
+// we need to find the layers of an image; puzzlefs images use their own index format, so these
+// can't be shared with crate::oci
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+const IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciDescriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct OciIndex {
+    manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    layers: Vec<OciDescriptor>,
+}
+
+fn unsupported(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+fn blob_path(oci_layout: &Path, digest: &str) -> io::Result<PathBuf> {
+    match digest.split_once(':') {
+        Some((algorithm, encoded))
+            if !algorithm.is_empty()
+                && !encoded.is_empty()
+                && !digest.contains(['/', '\0'])
+                && ![algorithm, encoded].contains(&"..") =>
+        {
+            Ok(oci_layout.join("blobs").join(algorithm).join(encoded))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid digest {digest}"),
+        )),
+    }
+}
+
+fn find_manifest(oci_layout: &Path, tag: &str) -> Result<OciManifest> {
+    let index_file = fs::File::open(oci_layout.join("index.json"))?;
+    let index = serde_json::from_reader::<_, OciIndex>(BufReader::new(index_file))?;
+    let desc = index
+        .manifests
+        .iter()
+        .find(|d| d.annotations.get(REF_NAME_ANNOTATION).map(String::as_str) == Some(tag))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no tag {tag} in {}", oci_layout.display()),
+            )
+        })?;
+
+    if desc.media_type == IMAGE_INDEX_MEDIA_TYPE {
+        return Err(unsupported(format!("{tag} is a multi-platform image index")).into());
+    }
+
+    let manifest_file = fs::File::open(blob_path(oci_layout, &desc.digest)?)?;
+    Ok(serde_json::from_reader(BufReader::new(manifest_file))?)
+}
+
+// layers are tar archives, optionally compressed, see
+// https://github.com/opencontainers/image-spec/blob/main/layer.md; docker media types end in
+// .tar.gzip rather than .tar+gzip
+fn open_layer(oci_layout: &Path, desc: &OciDescriptor) -> Result<Box<dyn Read>> {
+    let blob = BufReader::new(fs::File::open(blob_path(oci_layout, &desc.digest)?)?);
+    let media_type = desc.media_type.as_str();
+    if media_type.ends_with("+gzip") || media_type.ends_with(".gzip") {
+        Ok(Box::new(GzDecoder::new(blob)))
+    } else if media_type.ends_with("+zstd") {
+        Ok(Box::new(zstd::stream::read::Decoder::with_buffer(blob)?))
+    } else if media_type.ends_with("tar") {
+        Ok(Box::new(blob))
+    } else {
+        Err(unsupported(format!("unsupported layer media type {media_type}")).into())
+    }
+}
+
+/// Converts the image tagged `tag` in the OCI image layout at `oci_layout` into a puzzlefs
+/// image, rendering each OCI layer as a puzzlefs layer on top of the previous ones. The reports of
+/// the layers are returned from the bottom up.
+pub fn convert_oci_image<C: Compression + Any>(
+    oci_layout: &Path,
+    tag: &str,
+    oci: Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, Vec<BuildReport>)> {
+    let manifest = find_manifest(oci_layout, tag)?;
+    let oci = Arc::new(oci);
+    let excludes = Excludes::new(None, &options.exclude)?;
+    let mut tree = Tree::new();
+    let mut rootfs = None;
+    let mut reports = Vec::new();
+
+    for layer in &manifest.layers {
+        apply_layer(
+            &mut tree,
+            open_layer(oci_layout, layer)?,
+            true,
+            &excludes,
+            options,
+        )?;
+        let (layer_rootfs, report) = match rootfs {
+            None => initial_rootfs::<C>(&mut tree, &oci, options)?,
+            Some(rootfs) => delta_rootfs::<C>(&mut tree, &oci, rootfs, options)?,
+        };
+        rootfs = Some(layer_rootfs);
+        reports.push(report);
+    }
+
+    // an image without layers is an empty root filesystem
+    let rootfs = match rootfs {
+        Some(rootfs) => rootfs,
+        None => {
+            let (rootfs, report) = initial_rootfs::<C>(&mut tree, &oci, options)?;
+            reports.push(report);
+            rootfs
+        }
+    };
+    Ok((put_rootfs(&oci, rootfs)?, oci, reports))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use sha2::{Digest as _, Sha256};
+    use tar::{Builder, EntryType, Header};
+    use tempfile::tempdir;
+
+    use crate::compression::Noop;
+    use crate::format::InodeMode;
+    use crate::reader::PuzzleFS;
+
+    fn put_blob(oci_layout: &Path, data: &[u8]) -> String {
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(data)));
+        let path = blob_path(oci_layout, &digest).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+        digest
+    }
+
+    fn layer(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for (path, data) in entries {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_convert_oci_image() -> anyhow::Result<()> {
+        let oci_layout = tempdir()?;
+        let oci_layout = oci_layout.path();
+
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&layer(&[("etc/hostname", b"lower"), ("etc/hosts", b"")]))?;
+        let lower = put_blob(oci_layout, &gzip.finish()?);
+        let upper = zstd::encode_all(
+            &layer(&[("etc/.wh.hosts", b""), ("etc/hostname", b"upper")])[..],
+            3,
+        )?;
+        let upper = put_blob(oci_layout, &upper);
+
+        let manifest = put_blob(
+            oci_layout,
+            serde_json::json!({
+                "schemaVersion": 2,
+                "layers": [
+                    {"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": lower},
+                    {"mediaType": "application/vnd.oci.image.layer.v1.tar+zstd", "digest": upper},
+                ],
+            })
+            .to_string()
+            .as_bytes(),
+        );
+        fs::write(
+            oci_layout.join("index.json"),
+            serde_json::json!({
+                "schemaVersion": 2,
+                "manifests": [{
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": manifest,
+                    "annotations": {REF_NAME_ANNOTATION: "latest"},
+                }],
+            })
+            .to_string(),
+        )?;
+
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let (desc, image, reports) =
+            convert_oci_image::<Noop>(oci_layout, "latest", image, &BuildOptions::default())?;
+        image.add_tag("converted", desc)?;
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].whiteouts, 1);
+
+        let rootfs = image.open_rootfs_blob::<Noop>("converted", None)?;
+        assert_eq!(rootfs.metadatas.len(), 2);
+
+        let pfs = PuzzleFS::open(Image::open(dir.path())?, "converted", None)?;
+        assert!(pfs.lookup(Path::new("/etc/hosts"))?.is_none());
+        let hostname = pfs.lookup(Path::new("/etc/hostname"))?.unwrap();
+        let InodeMode::File { chunks } = hostname.mode else {
+            panic!("bad inode mode: {:?}", hostname.mode);
+        };
+        let mut contents = [0; 5];
+        assert_eq!(chunks.len(), 1);
+        pfs.oci
+            .fill_from_chunk(chunks[0].blob.unwrap(), 0, &mut contents, &None)?;
+        assert_eq!(&contents, b"upper");
+
+        assert!(convert_oci_image::<Noop>(
+            oci_layout,
+            "missing",
+            Image::open(dir.path())?,
+            &BuildOptions::default()
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::format::Result;
+
+// the file at the root of a directory rootfs listing the paths to leave out of the image
+pub(crate) const IGNORE_FILE: &str = ".puzzlefsignore";
+
+// the paths left out of an image, with the same syntax and semantics as .gitignore files: the last
+// rule matching a path wins, rules starting with ! include paths excluded by earlier ones, and
+// nothing inside an excluded directory can be included again
+pub(crate) struct Excludes(Gitignore);
+
+impl Excludes {
+    // the rules of the ignore file in rootfs, if any, followed by the given ones
+    pub(crate) fn new(rootfs: Option<&Path>, rules: &[String]) -> Result<Self> {
+        // candidate paths are always relative to the root of the tree
+        let mut builder = GitignoreBuilder::new("/");
+
+        if let Some(rootfs) = rootfs {
+            let path = rootfs.join(IGNORE_FILE);
+            match fs::read_to_string(&path) {
+                Ok(contents) => {
+                    for line in contents.lines() {
+                        builder
+                            .add_line(Some(path.clone()), line)
+                            .map_err(invalid_rule)?;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for rule in rules {
+            builder.add_line(None, rule).map_err(invalid_rule)?;
+        }
+
+        Ok(Excludes(builder.build().map_err(invalid_rule)?))
+    }
+
+    // path is relative to the root of the tree
+    pub(crate) fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        // tar archives may have paths like ./etc/passwd or /etc/passwd
+        let path = path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<PathBuf>();
+        self.0.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+}
+
+fn invalid_rule(e: ignore::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid exclude rule: {e}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_excludes() -> anyhow::Result<()> {
+        let rootfs = tempdir()?;
+        fs::write(
+            rootfs.path().join(IGNORE_FILE),
+            "# build artifacts\ntarget/\n*.o\n!keep.o\n/secret\n",
+        )?;
+        let excludes = Excludes::new(Some(rootfs.path()), &["!main.o".to_string()])?;
+
+        assert!(excludes.is_excluded(Path::new("target"), true));
+        assert!(!excludes.is_excluded(Path::new("target"), false));
+        assert!(excludes.is_excluded(Path::new("src/target/debug/app"), false));
+        assert!(excludes.is_excluded(Path::new("src/lib.o"), false));
+        assert!(!excludes.is_excluded(Path::new("src/keep.o"), false));
+        assert!(!excludes.is_excluded(Path::new("./main.o"), false));
+        assert!(excludes.is_excluded(Path::new("./secret"), false));
+        assert!(excludes.is_excluded(Path::new("/secret"), false));
+        assert!(!excludes.is_excluded(Path::new("src/secret"), false));
+        assert!(!excludes.is_excluded(Path::new(IGNORE_FILE), false));
+
+        // the rules of the command line come last, so they win
+        let excludes = Excludes::new(Some(rootfs.path()), &["!*.o".to_string()])?;
+        assert!(!excludes.is_excluded(Path::new("src/lib.o"), false));
+
+        assert!(Excludes::new(None, &["[z-a]".to_string()]).is_err());
+        assert!(!Excludes::new(None, &[])?.is_excluded(Path::new("a"), false));
+        Ok(())
+    }
+}
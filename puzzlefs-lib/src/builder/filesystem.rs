@@ -1,18 +1,92 @@
+use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use nix::errno::Errno;
+use nix::unistd::{lseek, Whence};
+
+enum Source {
+    Path(PathBuf),
+    // the data of a sparse file, without its holes
+    DataRanges {
+        path: PathBuf,
+        ranges: Vec<(u64, u64)>,
+    },
+    Range {
+        file: Arc<File>,
+        offset: u64,
+        len: u64,
+    },
+}
 
 struct ReaderLink {
-    file: PathBuf,
+    source: Source,
     done: bool,
 }
 
+// reads a range of a file shared with other readers, without touching its file offset
+pub(crate) struct RangeReader {
+    file: Arc<File>,
+    offset: u64,
+    remaining: u64,
+}
+
+impl RangeReader {
+    pub(crate) fn new(file: Arc<File>, offset: u64, len: u64) -> Self {
+        RangeReader {
+            file,
+            offset,
+            remaining: len,
+        }
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.file.read_at(&mut buf[..len], self.offset)?;
+        self.offset += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// the (offset, length) ranges of a file of the given length which hold data, i.e. which aren't
+// holes; a file system that doesn't know about holes has a single range
+pub(crate) fn data_ranges(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match lseek(fd, offset as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // there's no data after offset
+            Err(Errno::ENXIO) => break,
+            Err(Errno::EINVAL) if offset == 0 => return Ok(vec![(0, len)]),
+            Err(e) => return Err(e.into()),
+        };
+        let end = (lseek(fd, start as i64, Whence::SeekHole)? as u64).min(len);
+        if start >= end {
+            break;
+        }
+        ranges.push((start, end - start));
+        offset = end;
+    }
+    Ok(ranges)
+}
+
 /// A structure used to chain multiple readers, similar to
 /// [chain](https://doc.rust-lang.org/std/io/trait.Read.html#method.chain)
 /// and [multi_reader](https://docs.rs/multi_reader/latest/multi_reader/)
 pub struct FilesystemStream {
     reader_chain: Vec<ReaderLink>,
-    current_reader: Option<std::fs::File>,
+    current_reader: Option<Box<dyn Read>>,
 }
 
 impl FilesystemStream {
@@ -25,7 +99,24 @@ impl FilesystemStream {
 
     pub fn push(&mut self, file: &Path) {
         self.reader_chain.push(ReaderLink {
-            file: file.into(),
+            source: Source::Path(file.into()),
+            done: false,
+        })
+    }
+
+    pub fn push_data_ranges(&mut self, file: &Path, ranges: Vec<(u64, u64)>) {
+        self.reader_chain.push(ReaderLink {
+            source: Source::DataRanges {
+                path: file.into(),
+                ranges,
+            },
+            done: false,
+        })
+    }
+
+    pub fn push_range(&mut self, file: Arc<File>, offset: u64, len: u64) {
+        self.reader_chain.push(ReaderLink {
+            source: Source::Range { file, offset, len },
             done: false,
         })
     }
@@ -40,7 +131,22 @@ impl Read for FilesystemStream {
 
             let current_reader = match self.current_reader.as_mut() {
                 Some(reader) => reader,
-                None => self.current_reader.insert(std::fs::File::open(&link.file)?),
+                None => self.current_reader.insert(match &link.source {
+                    Source::Path(path) => Box::new(File::open(path)?),
+                    Source::DataRanges { path, ranges } => {
+                        let file = Arc::new(File::open(path)?);
+                        ranges.iter().fold(
+                            Box::new(io::empty()) as Box<dyn Read>,
+                            |reader, &(offset, len)| {
+                                let range = RangeReader::new(Arc::clone(&file), offset, len);
+                                Box::new(reader.chain(range))
+                            },
+                        )
+                    }
+                    Source::Range { file, offset, len } => {
+                        Box::new(RangeReader::new(Arc::clone(file), *offset, *len))
+                    }
+                }),
             };
 
             match current_reader.read(buf)? {
@@ -59,7 +165,6 @@ impl Read for FilesystemStream {
 pub mod tests {
     use super::*;
 
-    use std::fs::File;
     use std::io::Write;
     use tempfile::tempdir;
 
@@ -91,4 +196,46 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_data_ranges() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("sparse");
+        let file = File::create(&path)?;
+        file.set_len(10 << 20)?;
+        file.write_all_at(b"data", 5 << 20)?;
+        file.sync_all()?;
+
+        let ranges = data_ranges(&file, 10 << 20)?;
+        // file systems allocate whole blocks
+        assert!(!ranges.is_empty());
+        assert!(ranges[0].0 <= 5 << 20 && ranges[0].0 + ranges[0].1 >= (5 << 20) + 4);
+        assert!(ranges.iter().map(|r| r.1).sum::<u64>() < 1 << 20);
+
+        let mut fs_stream = FilesystemStream::new();
+        fs_stream.push_data_ranges(&path, vec![(5 << 20, 4), (0, 2)]);
+        let mut buffer = Vec::new();
+        fs_stream.read_to_end(&mut buffer)?;
+        assert_eq!(buffer, b"data\0\0");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_stream_ranges() -> anyhow::Result<()> {
+        let mut spool = tempfile::tempfile()?;
+        spool.write_all(b"Lorem ipsum dolor sit amet")?;
+        let spool = Arc::new(spool);
+        let mut buffer = Vec::new();
+
+        let mut fs_stream = FilesystemStream::new();
+        fs_stream.push_range(Arc::clone(&spool), 12, 6);
+        fs_stream.push_range(Arc::clone(&spool), 0, 0);
+        fs_stream.push_range(Arc::clone(&spool), 0, 12);
+
+        fs_stream.read_to_end(&mut buffer)?;
+        assert_eq!(buffer, "dolor Lorem ipsum ".as_bytes());
+
+        Ok(())
+    }
 }
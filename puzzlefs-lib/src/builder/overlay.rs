@@ -0,0 +1,366 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::exclude::Excludes;
+use super::rebase::tree_from_image;
+use super::tree::{empty_dir_list, Content, Node, NodeId, Tree, ROOT};
+use super::{delta_rootfs, put_rootfs, BuildOptions, BuildReport};
+use crate::compression::{Compression, Noop};
+use crate::format::{Inode, InodeAdditional, Result};
+use crate::oci::{Descriptor, Image};
+use crate::reader::PuzzleFS;
+
+// overlayfs marks deleted entries with 0/0 character devices and the directories that hide the
+// ones below with an xattr, see https://docs.kernel.org/filesystems/overlayfs.html. Its xattrs are
+// in the trusted namespace, or in the user one for overlays mounted with userxattr
+const OVERLAY_XATTR_PREFIXES: [&[u8]; 2] = [b"trusted.overlay.", b"user.overlay."];
+
+fn overlay_xattr(key: &[u8]) -> Option<&[u8]> {
+    OVERLAY_XATTR_PREFIXES
+        .iter()
+        .find_map(|prefix| key.strip_prefix(*prefix))
+}
+
+fn is_whiteout(md: &fs::Metadata) -> bool {
+    md.file_type().is_char_device() && md.rdev() == 0
+}
+
+// the xattrs of an entry without the ones of overlayfs, and whether it is an opaque directory
+fn overlay_additional(path: &Path, md: &fs::Metadata) -> Result<(Option<InodeAdditional>, bool)> {
+    match InodeAdditional::new(path, md)? {
+        Some(additional) => strip_overlay_xattrs(path, additional),
+        None => Ok((None, false)),
+    }
+}
+
+fn strip_overlay_xattrs(
+    path: &Path,
+    mut additional: InodeAdditional,
+) -> Result<(Option<InodeAdditional>, bool)> {
+    let mut opaque = false;
+    for xattr in &additional.xattrs {
+        match overlay_xattr(&xattr.key) {
+            Some(b"opaque") => opaque = xattr.val == b"y",
+            // the data of metacopy files and the contents of renamed directories are in the
+            // lower layers, which the image may not match
+            Some(name @ (b"metacopy" | b"redirect")) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "{} has the overlay {} xattr, which isn't supported",
+                        path.display(),
+                        name.escape_ascii()
+                    ),
+                )
+                .into())
+            }
+            _ => (),
+        }
+    }
+    additional
+        .xattrs
+        .retain(|xattr| overlay_xattr(&xattr.key).is_none());
+    if additional.xattrs.is_empty() && additional.symlink_target.is_none() {
+        return Ok((None, opaque));
+    }
+    Ok((Some(additional), opaque))
+}
+
+// applies an overlayfs upper directory on top of tree: its entries replace the ones of the tree,
+// except for directories, which are merged with the ones of the tree unless they are opaque, and
+// its whiteouts delete them
+fn apply_upperdir(tree: &mut Tree, upperdir: &Path, options: &BuildOptions) -> Result<()> {
+    let root_metadata = fs::symlink_metadata(upperdir)?;
+    let (additional, _) = overlay_additional(upperdir, &root_metadata)?;
+    tree.node_mut(ROOT).inode = Inode::new_dir(0, &root_metadata, empty_dir_list(), additional)?;
+
+    // host (device, inode) to node mapping, for hard link detection
+    let mut host_to_node = HashMap::<(u64, u64), NodeId>::new();
+    let mut dirs = vec![(upperdir.to_path_buf(), ROOT)];
+    while let Some((dir, parent)) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for e in entries {
+            let path = e.path();
+            let name = e.file_name();
+            let md = fs::symlink_metadata(&path)?;
+            options.cancellation.check()?;
+            options.notify(|o| o.file_walked(&path));
+
+            if is_whiteout(&md) {
+                tree.unlink(parent, &name);
+                continue;
+            }
+
+            let (additional, opaque) = overlay_additional(&path, &md)?;
+            if md.is_dir() {
+                let inode = Inode::new_dir(0, &md, empty_dir_list(), additional)?;
+                let existing = tree.node(parent).entries.get(&name).copied();
+                let node = match existing.filter(|&node| tree.node(node).is_dir() && !opaque) {
+                    Some(node) => {
+                        tree.node_mut(node).inode = inode;
+                        node
+                    }
+                    None => {
+                        let node = tree.add_node(Node::new(inode));
+                        tree.link(parent, &name, node);
+                        node
+                    }
+                };
+                dirs.push((path, node));
+                continue;
+            }
+
+            let node = match host_to_node.get(&(md.dev(), md.ino())) {
+                Some(&node) => node,
+                None => {
+                    let node = if md.is_file() {
+                        let inode = Inode::new_file(0, &md, Vec::new(), additional)?;
+                        let sparse = md.blocks() * 512 < md.len();
+                        let content = Content::Host { path, sparse };
+                        Node::new_file(inode, content, md.len())
+                    } else {
+                        Node::new(Inode::new_other(0, &md, additional)?)
+                    };
+                    let node = tree.add_node(node);
+                    host_to_node.insert((md.dev(), md.ino()), node);
+                    node
+                }
+            };
+            tree.link(parent, &name, node);
+        }
+    }
+    Ok(())
+}
+
+/// Adds a delta on top of the image tagged `tag` from an overlayfs upper directory, e.g. the one
+/// of a container started from the image: the upper directory holds the entries that were added
+/// or changed, 0/0 character devices for the ones that were deleted, and opaque directories that
+/// replace the ones of the image rather than being merged with them. The overlayfs xattrs are
+/// not part of the image. The report of the new layer is returned with its descriptor.
+pub fn add_overlay_delta<C: Compression + Any>(
+    upperdir: &Path,
+    oci: Image,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, BuildReport)> {
+    let oci = Arc::new(oci);
+    let rootfs = oci.open_rootfs_blob::<Noop>(tag, None)?;
+    let pfs = PuzzleFS::from_rootfs(Arc::clone(&oci), &rootfs, None)?;
+    let mut tree = tree_from_image(&pfs, options)?;
+    apply_upperdir(&mut tree, upperdir, options)?;
+    // like for other deltas, the excluded paths of the image are hidden as well
+    tree.prune(&Excludes::new(Some(upperdir), &options.exclude)?);
+
+    let (rootfs, report) = delta_rootfs::<C>(&mut tree, &oci, rootfs, options)?;
+    Ok((put_rootfs(&oci, rootfs)?, oci, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use nix::sys::stat::{mknod, Mode, SFlag};
+    use tempfile::tempdir;
+
+    use crate::builder::build_initial_rootfs;
+    use crate::compression::Zstd;
+    use crate::format::{InodeMode, WireFormatError, Xattr};
+    use crate::reader::FileReader;
+
+    fn build_base(dir: &Path) -> anyhow::Result<()> {
+        let rootfs = dir.join("rootfs");
+        for path in [
+            "etc/a",
+            "etc/b",
+            "var/cache/x",
+            "var/cache/y",
+            "usr/bin/tool",
+        ] {
+            let path = rootfs.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, b"base")?;
+        }
+        let image = Image::new(&dir.join("image"))?;
+        image.add_tag(
+            "base",
+            build_initial_rootfs::<Zstd>(&rootfs, &image, &BuildOptions::default())?,
+        )?;
+        Ok(())
+    }
+
+    fn read(pfs: &PuzzleFS, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(inode) = pfs.lookup(Path::new(path))? else {
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        FileReader::new(&pfs.oci, &inode)?.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn layer_inodes(image: &Image, tag: &str) -> anyhow::Result<Vec<Inode>> {
+        let rootfs = image.open_rootfs_blob::<Noop>(tag, None)?;
+        let layer = image.open_metadata_blob(&rootfs.metadatas[0].try_into()?, None)?;
+        Ok(layer
+            .get_inode_vector()?
+            .iter()
+            .map(Inode::from_capnp)
+            .collect::<Result<Vec<_>>>()?)
+    }
+
+    fn whiteouts(inodes: &[Inode]) -> usize {
+        inodes
+            .iter()
+            .filter(|inode| matches!(inode.mode, InodeMode::Wht))
+            .count()
+    }
+
+    #[test]
+    fn test_overlay_delta() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        build_base(dir.path())?;
+        let image_dir = dir.path().join("image");
+
+        let upper = dir.path().join("upper");
+        fs::create_dir_all(upper.join("etc"))?;
+        fs::create_dir_all(upper.join("var/cache"))?;
+        fs::write(upper.join("etc/a"), b"upper")?;
+        fs::write(upper.join("etc/c"), b"new")?;
+        fs::write(upper.join("var/cache/z"), b"new")?;
+
+        let (desc, image, _) = add_overlay_delta::<Zstd>(
+            &upper,
+            Image::open(&image_dir)?,
+            "base",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("overlay", desc)?;
+        let pfs = PuzzleFS::open(Image::open(&image_dir)?, "overlay", None)?;
+        // the directories of the upper directory are merged with the ones of the image
+        assert_eq!(read(&pfs, "/etc/a")?.as_deref(), Some(&b"upper"[..]));
+        assert_eq!(read(&pfs, "/etc/b")?.as_deref(), Some(&b"base"[..]));
+        assert_eq!(read(&pfs, "/etc/c")?.as_deref(), Some(&b"new"[..]));
+        assert_eq!(read(&pfs, "/usr/bin/tool")?.as_deref(), Some(&b"base"[..]));
+        assert_eq!(read(&pfs, "/var/cache/x")?.as_deref(), Some(&b"base"[..]));
+        assert_eq!(read(&pfs, "/var/cache/z")?.as_deref(), Some(&b"new"[..]));
+
+        // the delta only has the files that changed
+        let inodes = layer_inodes(&image, "overlay")?;
+        assert_eq!(whiteouts(&inodes), 0);
+        let base = PuzzleFS::open(Image::open(&image_dir)?, "base", None)?;
+        for path in ["/etc/b", "/usr/bin/tool", "/var/cache/x"] {
+            let inode = base.lookup(Path::new(path))?.unwrap();
+            assert!(inodes.iter().all(|i| i.ino != inode.ino));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlay_xattrs() -> anyhow::Result<()> {
+        let path = Path::new("upper/dir");
+        let additional = |xattrs: &[(&str, &str)]| InodeAdditional {
+            xattrs: xattrs
+                .iter()
+                .map(|(key, val)| Xattr {
+                    key: key.as_bytes().to_vec(),
+                    val: val.as_bytes().to_vec(),
+                })
+                .collect(),
+            symlink_target: None,
+        };
+
+        let (stripped, opaque) = strip_overlay_xattrs(
+            path,
+            additional(&[
+                ("user.overlay.origin", "origin"),
+                ("user.label", "kept"),
+                ("trusted.overlay.opaque", "y"),
+            ]),
+        )?;
+        assert!(opaque);
+        assert_eq!(stripped, Some(additional(&[("user.label", "kept")])));
+
+        let (stripped, opaque) =
+            strip_overlay_xattrs(path, additional(&[("user.overlay.origin", "origin")]))?;
+        assert!(!opaque);
+        assert_eq!(stripped, None);
+
+        // overlayfs only hides the lower directory when the value is "y"
+        let (_, opaque) = strip_overlay_xattrs(path, additional(&[("user.overlay.opaque", "x")]))?;
+        assert!(!opaque);
+
+        for key in [
+            "user.overlay.metacopy",
+            "trusted.overlay.metacopy",
+            "user.overlay.redirect",
+            "trusted.overlay.redirect",
+        ] {
+            let err = strip_overlay_xattrs(path, additional(&[(key, "")])).unwrap_err();
+            assert!(
+                matches!(&err, WireFormatError::IOError(e, _) if e.kind() == io::ErrorKind::Unsupported),
+                "{key}: {err}"
+            );
+        }
+        Ok(())
+    }
+
+    // whiteouts are character devices, which need CAP_MKNOD, and the filesystem of the temporary
+    // directory needs user xattrs
+    #[test]
+    #[ignore = "needs CAP_MKNOD and user xattrs, run with --ignored as root"]
+    fn test_overlay_whiteouts() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        build_base(dir.path())?;
+        let image_dir = dir.path().join("image");
+
+        let upper = dir.path().join("upper");
+        fs::create_dir_all(upper.join("etc"))?;
+        fs::create_dir_all(upper.join("var/cache"))?;
+        fs::write(upper.join("etc/a"), b"upper")?;
+        fs::write(upper.join("var/cache/z"), b"new")?;
+        mknod(&upper.join("etc/b"), SFlag::S_IFCHR, Mode::empty(), 0)?;
+        xattr::set(upper.join("var/cache"), "user.overlay.opaque", b"y")?;
+        xattr::set(upper.join("etc/a"), "user.overlay.origin", b"origin")?;
+
+        let (desc, image, _) = add_overlay_delta::<Zstd>(
+            &upper,
+            Image::open(&image_dir)?,
+            "base",
+            &BuildOptions::default(),
+        )?;
+        image.add_tag("overlay", desc)?;
+        let pfs = PuzzleFS::open(Image::open(&image_dir)?, "overlay", None)?;
+        assert_eq!(read(&pfs, "/etc/a")?.as_deref(), Some(&b"upper"[..]));
+        assert_eq!(read(&pfs, "/etc/b")?, None);
+        assert_eq!(read(&pfs, "/var/cache/x")?, None);
+        assert_eq!(read(&pfs, "/var/cache/z")?.as_deref(), Some(&b"new"[..]));
+        assert!(pfs
+            .lookup(Path::new("/etc/a"))?
+            .unwrap()
+            .additional
+            .is_none());
+
+        // etc/b, and var/cache/x and y below the opaque directory
+        let inodes = layer_inodes(&image, "overlay")?;
+        assert_eq!(whiteouts(&inodes), 3);
+
+        // the lower directory of a renamed one may not be the one of the image
+        fs::create_dir(upper.join("moved"))?;
+        xattr::set(upper.join("moved"), "user.overlay.redirect", b"/usr")?;
+        assert!(add_overlay_delta::<Zstd>(
+            &upper,
+            Image::open(&image_dir)?,
+            "base",
+            &BuildOptions::default(),
+        )
+        .is_err());
+        Ok(())
+    }
+}
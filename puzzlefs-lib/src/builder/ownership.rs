@@ -0,0 +1,185 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use super::tree::Node;
+use super::BuildOptions;
+use crate::format::Canonicalization;
+
+/// A range of ids, in the format of the lines of `/proc/<pid>/uid_map` and `gid_map` (see
+/// user_namespaces(7)): the `count` ids starting at `outside`, i.e. the owners of the files on the
+/// host, are the `count` ids starting at `inside` in the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMapping {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl IdMapping {
+    fn outside_end(&self) -> u64 {
+        u64::from(self.outside) + u64::from(self.count)
+    }
+
+    fn inside_end(&self) -> u64 {
+        u64::from(self.inside) + u64::from(self.count)
+    }
+}
+
+impl FromStr for IdMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let fields = s
+            .split_whitespace()
+            .map(|field| field.parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid id mapping {s}: {e}"))?;
+        match fields[..] {
+            [inside, outside, count] => Ok(IdMapping {
+                inside,
+                outside,
+                count,
+            }),
+            _ => Err(format!(
+                "invalid id mapping {s}, expected \"inside outside count\""
+            )),
+        }
+    }
+}
+
+/// The uid or gid mapping applied to the owners of the files of an image. Like for user
+/// namespaces, the ranges can't overlap; unlike for user namespaces, the ids that aren't mapped
+/// are kept as they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IdMap(Vec<IdMapping>);
+
+impl IdMap {
+    pub fn new(mappings: Vec<IdMapping>) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        for (i, m) in mappings.iter().enumerate() {
+            if m.count == 0 || m.outside_end() > 1 << 32 || m.inside_end() > 1 << 32 {
+                return Err(invalid(format!("invalid id mapping {m:?}")));
+            }
+            for other in &mappings[..i] {
+                let outside_overlap = u64::from(m.outside) < other.outside_end()
+                    && u64::from(other.outside) < m.outside_end();
+                let inside_overlap = u64::from(m.inside) < other.inside_end()
+                    && u64::from(other.inside) < m.inside_end();
+                if outside_overlap || inside_overlap {
+                    return Err(invalid(format!(
+                        "overlapping id mappings {other:?} and {m:?}"
+                    )));
+                }
+            }
+        }
+        Ok(IdMap(mappings))
+    }
+
+    pub fn map(&self, id: u32) -> u32 {
+        self.0
+            .iter()
+            .find(|m| id >= m.outside && u64::from(id) < m.outside_end())
+            .map(|m| m.inside + (id - m.outside))
+            .unwrap_or(id)
+    }
+}
+
+/// Gives everything at and below `path` (relative to the root of the image) the same owner,
+/// whatever the owner of the files in the root filesystem is, e.g. `/srv/app=1000:1000`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnerOverride {
+    pub path: PathBuf,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FromStr for OwnerOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid owner override {s}, expected path=uid:gid");
+        let (path, owner) = s.rsplit_once('=').ok_or_else(invalid)?;
+        let (uid, gid) = owner.split_once(':').ok_or_else(invalid)?;
+        Ok(OwnerOverride {
+            path: PathBuf::from(path),
+            uid: uid.parse().map_err(|_| invalid())?,
+            gid: gid.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+fn names(path: &Path) -> impl Iterator<Item = Component<'_>> {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+}
+
+// the owner of the node at path (relative to the root of the image) once rendered: the most
+// specific override, if any, otherwise root for canonical ownership or the mapped owner of the
+// node. Hard links are rendered once, so their owner only depends on the first of their paths in
+// the order of the build
+pub(crate) fn owner(
+    options: &BuildOptions,
+    canonicalization: Option<&Canonicalization>,
+    node: &Node,
+    path: &Path,
+) -> (u32, u32) {
+    let depth = |o: &OwnerOverride| names(&o.path).count();
+    let is_below = |o: &OwnerOverride| {
+        let mut path = names(path);
+        names(&o.path).all(|name| path.next() == Some(name))
+    };
+    match options
+        .force_owner
+        .iter()
+        .filter(|o| is_below(o))
+        .max_by_key(|o| depth(o))
+    {
+        Some(o) => (o.uid, o.gid),
+        None if canonicalization.is_some_and(|c| c.normalize_ownership) => (0, 0),
+        None => (
+            options.uid_map.map(node.inode.uid),
+            options.gid_map.map(node.inode.gid),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_map() {
+        let map = IdMap::new(vec![
+            "0 100000 1000".parse().unwrap(),
+            "1000 1000 1".parse().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(map.map(100000), 0);
+        assert_eq!(map.map(100999), 999);
+        assert_eq!(map.map(101000), 101000);
+        assert_eq!(map.map(1000), 1000);
+        assert_eq!(map.map(5), 5);
+
+        assert!("0 100000".parse::<IdMapping>().is_err());
+        assert!("0 -1 1".parse::<IdMapping>().is_err());
+        for invalid in [
+            "0 0 0",
+            "1 4294967295 2",
+            "0 0 10\n5 100 10",
+            "0 0 10\n100 5 10",
+        ] {
+            let mappings = invalid.lines().map(|l| l.parse().unwrap()).collect();
+            assert!(IdMap::new(mappings).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_owner_override() {
+        let o = "/srv/a=b=1000:100".parse::<OwnerOverride>().unwrap();
+        assert_eq!(o.path, Path::new("/srv/a=b"));
+        assert_eq!((o.uid, o.gid), (1000, 100));
+        assert!("/srv=1000".parse::<OwnerOverride>().is_err());
+        assert!("/srv=a:b".parse::<OwnerOverride>().is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::format::{Inode, InodeMode};
+
+/// Receives the events of a build, e.g. to show its progress. The events of the chunks are sent
+/// from the threads that write them, in no particular order.
+pub trait BuildObserver: Send + Sync {
+    /// An entry of the root filesystem, or of a tar archive, was read.
+    fn file_walked(&self, _path: &Path) {}
+    /// The files of a layer are about to be chunked, `total` bytes in all.
+    fn chunking_started(&self, _total: u64) {}
+    /// A chunk of `len` bytes was cut from the files.
+    fn bytes_chunked(&self, _len: u64) {}
+    /// A chunk of `len` bytes was written to the image, or was already in it if `deduplicated`.
+    fn chunk_stored(&self, _len: u64, _deduplicated: bool) {}
+    /// A metadata blob of `len` bytes was serialized.
+    fn metadata_serialized(&self, _len: u64) {}
+    /// A layer was written to the image.
+    fn layer_built(&self, _report: &BuildReport) {}
+}
+
+/// What a layer is made of and what it cost to store it.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildReport {
+    /// The inodes of the layer by type; a delta only has the inodes that changed.
+    pub files: u64,
+    pub directories: u64,
+    pub other_inodes: u64,
+    pub whiteouts: u64,
+    /// The size of the files of the layer.
+    pub logical_bytes: u64,
+    /// The chunks of the files that were read, rather than reused from the layers below, and their
+    /// size before and after compression.
+    pub chunks: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
+    /// The chunk and metadata blobs that were written to the image, and the ones that were already
+    /// in it.
+    pub new_blobs: u64,
+    pub existing_blobs: u64,
+    pub metadata_bytes: u64,
+}
+
+impl BuildReport {
+    pub(crate) fn count_inodes(&mut self, inodes: &[Inode]) {
+        for inode in inodes {
+            match &inode.mode {
+                InodeMode::File { chunks } => {
+                    self.files += 1;
+                    self.logical_bytes += chunks.iter().map(|c| c.len).sum::<u64>();
+                }
+                InodeMode::Dir { .. } => self.directories += 1,
+                InodeMode::Wht => self.whiteouts += 1,
+                _ => self.other_inodes += 1,
+            }
+        }
+    }
+
+    pub(crate) fn count_blob(&mut self, existing: bool) {
+        if existing {
+            self.existing_blobs += 1;
+        } else {
+            self.new_blobs += 1;
+        }
+    }
+}
+
+impl fmt::Debug for dyn BuildObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BuildObserver")
+    }
+}
+
+/// Stops a build from another thread: the build fails with [io::ErrorKind::Interrupted] the next
+/// time it checks the token, which is at least between two chunks. A cancelled build returns no
+/// descriptor to tag, so it only leaves blobs that nothing refers to behind.
+#[derive(Debug, Default, Clone)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "build cancelled",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
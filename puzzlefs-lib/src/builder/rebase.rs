@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Arc;
+
+use super::tree::{empty_dir_list, Content, Node, NodeId, Tree, ROOT};
+use super::{delta_rootfs, put_rootfs, BuildOptions, BuildReport};
+use crate::compression::Noop;
+use crate::format::{Ino, Inode, InodeMode, Result, Rootfs, SHA256_BLOCK_SIZE};
+use crate::oci::{Descriptor, Image};
+use crate::reader::PuzzleFS;
+
+// the rootfs made of the layers of rootfs from `from` down
+fn lower_layers(rootfs: &Rootfs, from: usize) -> Rootfs {
+    Rootfs {
+        metadatas: rootfs.metadatas[from..].to_vec(),
+        fs_verity_data: rootfs.fs_verity_data.clone(),
+        manifest_version: rootfs.manifest_version,
+        chunk_params: rootfs.chunk_params,
+        canonicalization: rootfs.canonicalization.clone(),
+    }
+}
+
+// an inode of an image as a node of the tree, without its directory entries or its chunks, which
+// the tree keeps on its own
+fn tree_inode(inode: &Inode) -> Inode {
+    let mode = match &inode.mode {
+        InodeMode::Dir { .. } => InodeMode::Dir {
+            dir_list: empty_dir_list(),
+        },
+        InodeMode::File { .. } => InodeMode::File { chunks: Vec::new() },
+        mode => mode.clone(),
+    };
+    Inode {
+        mode,
+        nlink: 1,
+        ..inode.clone()
+    }
+}
+
+fn image_node(inode: &Inode, digests: &mut BTreeSet<[u8; SHA256_BLOCK_SIZE]>) -> Result<Node> {
+    match &inode.mode {
+        InodeMode::File { chunks } => {
+            digests.extend(
+                chunks
+                    .iter()
+                    .filter_map(|chunk| chunk.blob.map(|b| b.digest)),
+            );
+            let content = Content::Image {
+                chunks: chunks.clone(),
+            };
+            Ok(Node::new_file(
+                tree_inode(inode),
+                content,
+                inode.file_len()?,
+            ))
+        }
+        _ => Ok(Node::new(tree_inode(inode))),
+    }
+}
+
+// replays the changes from the lower to the upper view of an image on the tree: the names that
+// were deleted are unlinked, and the entries that were added or changed replace whatever the tree
+// has at their path. Directories are merged with the ones of the tree; the changes below the ones
+// that the tree doesn't have are dropped, unless the directory itself changed. Returns the digests
+// of the chunks of the files that were added.
+fn apply_changes(
+    tree: &mut Tree,
+    lower: Option<&PuzzleFS>,
+    upper: &PuzzleFS,
+    options: &BuildOptions,
+) -> Result<BTreeSet<[u8; SHA256_BLOCK_SIZE]>> {
+    let mut digests = BTreeSet::new();
+    // the nodes of the inodes of the upper view, so that hard links stay hard links
+    let mut nodes = HashMap::<Ino, NodeId>::new();
+
+    let upper_root = upper.find_inode(1)?;
+    let lower_root = lower.map(|pfs| pfs.find_inode(1)).transpose()?;
+    if lower_root.as_ref().map(tree_inode) != Some(tree_inode(&upper_root)) {
+        tree.node_mut(ROOT).inode = tree_inode(&upper_root);
+    }
+    let mut dirs = vec![(ROOT, lower_root, upper_root)];
+
+    while let Some((dir_node, lower_dir, upper_dir)) = dirs.pop() {
+        options.cancellation.check()?;
+        let lower_entries = match &lower_dir {
+            Some(dir) => dir
+                .dir_entries()?
+                .iter()
+                .map(|dir_ent| (&dir_ent.name[..], dir_ent.ino))
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        let upper_entries = upper_dir.dir_entries()?;
+
+        for name in lower_entries.keys() {
+            if !upper_entries.iter().any(|dir_ent| dir_ent.name == *name) {
+                tree.unlink(dir_node, OsStr::from_bytes(name));
+            }
+        }
+
+        for dir_ent in upper_entries {
+            let name = OsStr::from_bytes(&dir_ent.name);
+            let upper_inode = upper.find_inode(dir_ent.ino)?;
+            let lower_inode = match (lower, lower_entries.get(&dir_ent.name[..])) {
+                (Some(lower), Some(&ino)) => Some(lower.find_inode(ino)?),
+                _ => None,
+            };
+            let unchanged = lower_inode.as_ref() == Some(&upper_inode);
+            let existing = tree.node(dir_node).entries.get(name).copied();
+
+            if let InodeMode::Dir { .. } = upper_inode.mode {
+                let node = match existing.filter(|&node| tree.node(node).is_dir()) {
+                    Some(node) => {
+                        if lower_inode.as_ref().map(tree_inode) != Some(tree_inode(&upper_inode)) {
+                            tree.node_mut(node).inode = tree_inode(&upper_inode);
+                        }
+                        node
+                    }
+                    None if unchanged => continue,
+                    None => {
+                        let node = tree.add_node(image_node(&upper_inode, &mut digests)?);
+                        tree.link(dir_node, name, node);
+                        node
+                    }
+                };
+                let lower_dir = lower_inode.filter(|inode| inode.dir_entries().is_ok());
+                dirs.push((node, lower_dir, upper_inode));
+            } else if unchanged {
+                // the tree keeps its own version, unless the inode got a new name that was already
+                // added to the tree
+                match (nodes.get(&dir_ent.ino), existing) {
+                    (Some(&node), Some(_)) => tree.link(dir_node, name, node),
+                    (None, Some(node)) => {
+                        nodes.insert(dir_ent.ino, node);
+                    }
+                    (_, None) => (),
+                }
+            } else {
+                let node = match nodes.get(&dir_ent.ino) {
+                    Some(&node) => node,
+                    None => {
+                        let node = tree.add_node(image_node(&upper_inode, &mut digests)?);
+                        nodes.insert(dir_ent.ino, node);
+                        node
+                    }
+                };
+                tree.link(dir_node, name, node);
+            }
+        }
+    }
+    Ok(digests)
+}
+
+// the tree of the files of an image, which keep their chunks
+pub(crate) fn tree_from_image(pfs: &PuzzleFS, options: &BuildOptions) -> Result<Tree> {
+    let mut tree = Tree::new();
+    apply_changes(&mut tree, None, pfs, options)?;
+    Ok(tree)
+}
+
+/// Moves the layers that the image tagged `tag` has on top of the image tagged `old_base` onto
+/// the image tagged `new_base`, e.g. to update the base OS of an application image without
+/// rebuilding it. Each layer is rendered again as the changes it makes, with the inode numbers of
+/// the new base and whiteouts for the names the new base has. The files keep their chunks, which
+/// are not read. The reports of the layers are returned from the bottom up.
+pub fn rebase_rootfs(
+    oci: Image,
+    tag: &str,
+    old_base: &str,
+    new_base: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, Vec<BuildReport>)> {
+    let oci = Arc::new(oci);
+    let rootfs = oci.open_rootfs_blob::<Noop>(tag, None)?;
+    let old_base_rootfs = oci.open_rootfs_blob::<Noop>(old_base, None)?;
+    let top = rootfs
+        .metadatas
+        .len()
+        .checked_sub(old_base_rootfs.metadatas.len())
+        .filter(|&top| rootfs.metadatas[top..] == old_base_rootfs.metadatas[..])
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{tag} is not built on top of {old_base}"),
+            )
+        })?;
+
+    let mut new_rootfs = oci.open_rootfs_blob::<Noop>(new_base, None)?;
+    let base = PuzzleFS::from_rootfs(Arc::clone(&oci), &new_rootfs, None)?;
+    let mut tree = tree_from_image(&base, options)?;
+
+    // the layers are replayed from the bottom up
+    let mut digests = BTreeSet::new();
+    let mut reports = Vec::new();
+    for layer in (0..top).rev() {
+        let lower =
+            PuzzleFS::from_rootfs(Arc::clone(&oci), &lower_layers(&rootfs, layer + 1), None)?;
+        let upper = PuzzleFS::from_rootfs(Arc::clone(&oci), &lower_layers(&rootfs, layer), None)?;
+        digests.extend(apply_changes(&mut tree, Some(&lower), &upper, options)?);
+        let (rootfs, report) = delta_rootfs::<Noop>(&mut tree, &oci, new_rootfs, options)?;
+        new_rootfs = rootfs;
+        reports.push(report);
+    }
+
+    // the chunks of the files are the ones of the layers
+    for digest in digests {
+        if let Some(verity) = rootfs.fs_verity_data.get(&digest) {
+            new_rootfs.fs_verity_data.insert(digest, *verity);
+        }
+    }
+    Ok((put_rootfs(&oci, new_rootfs)?, oci, reports))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use crate::builder::{add_rootfs_delta, build_initial_rootfs};
+    use crate::compression::Zstd;
+    use crate::reader::FileReader;
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) -> anyhow::Result<()> {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+
+    fn read(pfs: &PuzzleFS, path: &str) -> anyhow::Result<Option<String>> {
+        let Some(inode) = pfs.lookup(Path::new(path))? else {
+            return Ok(None);
+        };
+        let mut data = String::new();
+        FileReader::new(&pfs.oci, &inode)?.read_to_string(&mut data)?;
+        Ok(Some(data))
+    }
+
+    #[test]
+    fn test_rebase_rootfs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image_dir = dir.path().join("image");
+        let image = Image::new(&image_dir)?;
+        let options = BuildOptions::default();
+
+        let base = dir.path().join("base");
+        write_files(
+            &base,
+            &[
+                ("etc/os-release", "v1"),
+                ("etc/passwd", "root"),
+                ("etc/motd", "hello"),
+                ("usr/lib/libc", "libc v1"),
+                ("var/old/file", "old"),
+            ],
+        )?;
+        image.add_tag(
+            "base",
+            build_initial_rootfs::<Zstd>(&base, &image, &options)?,
+        )?;
+
+        // the application changes a file of the base, deletes one and adds its own in two layers
+        let app = base;
+        write_files(&app, &[("etc/passwd", "root app"), ("app/bin", "app")])?;
+        fs::remove_file(app.join("etc/motd"))?;
+        fs::hard_link(app.join("app/bin"), app.join("app/link"))?;
+        let (desc, image) = add_rootfs_delta::<Zstd>(&app, image, "base", &options)?;
+        image.add_tag("app-1", desc)?;
+        write_files(&app, &[("app/config", "config")])?;
+        let (desc, image) =
+            add_rootfs_delta::<Zstd>(&app, Image::open(&image_dir)?, "app-1", &options)?;
+        image.add_tag("app", desc)?;
+
+        // the new base has more inodes than the old one, and no longer has var/old
+        let new_base = dir.path().join("new-base");
+        write_files(
+            &new_base,
+            &[
+                ("etc/os-release", "v2"),
+                ("etc/passwd", "root"),
+                ("etc/motd", "hello"),
+                ("usr/lib/libc", "libc v2"),
+                ("usr/lib/libm", "libm"),
+                ("usr/lib/libz", "libz"),
+            ],
+        )?;
+        let desc = build_initial_rootfs::<Zstd>(&new_base, &Image::open(&image_dir)?, &options)?;
+        image.add_tag("new-base", desc)?;
+        let blobs = fs::read_dir(image.blob_path())?.count();
+
+        let (desc, image, reports) = rebase_rootfs(
+            Image::open(&image_dir)?,
+            "app",
+            "base",
+            "new-base",
+            &options,
+        )?;
+        image.add_tag("rebased", desc)?;
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|report| report.chunks == 0));
+        let rootfs = image.open_rootfs_blob::<Noop>("rebased", None)?;
+        assert_eq!(rootfs.metadatas.len(), 3);
+        // a metadata blob for each layer and the manifest, no chunks
+        assert_eq!(fs::read_dir(image.blob_path())?.count(), blobs + 3);
+
+        let pfs = PuzzleFS::open(Image::open(&image_dir)?, "rebased", None)?;
+        assert_eq!(read(&pfs, "/etc/os-release")?.as_deref(), Some("v2"));
+        assert_eq!(read(&pfs, "/etc/passwd")?.as_deref(), Some("root app"));
+        assert_eq!(read(&pfs, "/etc/motd")?, None);
+        assert_eq!(read(&pfs, "/usr/lib/libc")?.as_deref(), Some("libc v2"));
+        assert_eq!(read(&pfs, "/usr/lib/libm")?.as_deref(), Some("libm"));
+        assert_eq!(read(&pfs, "/app/bin")?.as_deref(), Some("app"));
+        assert_eq!(read(&pfs, "/app/config")?.as_deref(), Some("config"));
+        assert!(pfs.lookup(Path::new("/var/old"))?.is_none());
+
+        let new_base_max =
+            PuzzleFS::open(Image::open(&image_dir)?, "new-base", None)?.max_inode()?;
+        let bin = pfs.lookup(Path::new("/app/bin"))?.unwrap();
+        assert!(bin.ino > new_base_max);
+        assert_eq!(bin.nlink, 2);
+        assert_eq!(pfs.lookup(Path::new("/app/link"))?.unwrap().ino, bin.ino);
+
+        assert!(rebase_rootfs(
+            Image::open(&image_dir)?,
+            "app",
+            "new-base",
+            "base",
+            &options
+        )
+        .is_err());
+        Ok(())
+    }
+}
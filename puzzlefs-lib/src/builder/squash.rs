@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use super::{put_metadata, put_rootfs, BuildOptions, BuildReport};
+use crate::compression::Noop;
+use crate::format::{BlobRef, InodeMode, Result, VerityData};
+use crate::oci::{Descriptor, Image};
+use crate::reader::{PuzzleFS, PUZZLEFS_IMAGE_MANIFEST_VERSION};
+
+/// Flattens the layers of the image tagged `tag` into a single layer, so that reads don't have to
+/// search through all of them and the whiteouts are gone. The files keep their chunks, which are
+/// not read, and the inodes keep their numbers. The report of the new layer is returned with its
+/// descriptor.
+pub fn squash_rootfs(
+    oci: Image,
+    tag: &str,
+    options: &BuildOptions,
+) -> Result<(Descriptor, Arc<Image>, BuildReport)> {
+    let oci = Arc::new(oci);
+    let mut rootfs = oci.open_rootfs_blob::<Noop>(tag, None)?;
+    let pfs = PuzzleFS::from_rootfs(Arc::clone(&oci), &rootfs, None)?;
+
+    // the reader already merges the directories of all the layers, only the inodes that can be
+    // reached from the root are kept
+    let mut inodes = Vec::new();
+    let mut seen = HashSet::from([1]);
+    let mut pending = vec![1];
+    while let Some(ino) = pending.pop() {
+        options.cancellation.check()?;
+        let inode = pfs.find_inode(ino)?;
+        if let InodeMode::Dir { dir_list } = &inode.mode {
+            for dir_ent in &dir_list.entries {
+                if seen.insert(dir_ent.ino) {
+                    pending.push(dir_ent.ino);
+                }
+            }
+        }
+        inodes.push(inode);
+    }
+    inodes.sort_by_key(|inode| inode.ino);
+
+    // the verity data of the metadata of the old layers isn't needed anymore
+    let mut verity_data: VerityData = BTreeMap::new();
+    for inode in &inodes {
+        if let InodeMode::File { chunks } = &inode.mode {
+            for blob in chunks.iter().filter_map(|chunk| chunk.blob) {
+                if let Some(verity) = rootfs.fs_verity_data.get(&blob.digest) {
+                    verity_data.insert(blob.digest, *verity);
+                }
+            }
+        }
+    }
+
+    let mut report = BuildReport::default();
+    report.count_inodes(&inodes);
+    let desc = put_metadata(&oci, &inodes, &mut verity_data, &mut report, options)?;
+    options.notify(|o| o.layer_built(&report));
+
+    rootfs.metadatas = vec![BlobRef {
+        digest: desc.digest.underlying(),
+        offset: 0,
+        compressed: false,
+    }];
+    rootfs.fs_verity_data = verity_data;
+    // the layer may be sharded even if the old ones weren't
+    rootfs.manifest_version = PUZZLEFS_IMAGE_MANIFEST_VERSION;
+    Ok((put_rootfs(&oci, rootfs)?, oci, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use tempfile::tempdir;
+
+    use crate::builder::{add_rootfs_delta, build_initial_rootfs};
+    use crate::compression::Zstd;
+    use crate::format::Inode;
+    use crate::reader::WalkPuzzleFS;
+
+    fn walk(image: &Path, tag: &str) -> anyhow::Result<Vec<(PathBuf, Inode)>> {
+        let mut pfs = PuzzleFS::open(Image::open(image)?, tag, None)?;
+        WalkPuzzleFS::walk(&mut pfs)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok((entry.path, entry.inode))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_squash_rootfs() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let rootfs = dir.path().join("rootfs");
+        let image_dir = dir.path().join("image");
+        fs::create_dir_all(rootfs.join("etc"))?;
+        fs::write(rootfs.join("etc/a"), b"a")?;
+        fs::write(rootfs.join("etc/b"), b"b")?;
+        fs::hard_link(rootfs.join("etc/a"), rootfs.join("link"))?;
+
+        let image = Image::new(&image_dir)?;
+        let options = BuildOptions::default();
+        image.add_tag(
+            "base",
+            build_initial_rootfs::<Zstd>(&rootfs, &image, &options)?,
+        )?;
+        fs::remove_file(rootfs.join("etc/b"))?;
+        fs::write(rootfs.join("etc/c"), b"c")?;
+        let (desc, image) = add_rootfs_delta::<Zstd>(&rootfs, image, "base", &options)?;
+        image.add_tag("delta", desc)?;
+        let blobs = fs::read_dir(image.blob_path())?.count();
+
+        let (desc, image, report) = squash_rootfs(Image::open(&image_dir)?, "delta", &options)?;
+        image.add_tag("squashed", desc)?;
+        assert_eq!((report.files, report.directories), (2, 2));
+        let squashed = image.open_rootfs_blob::<Noop>("squashed", None)?;
+        assert_eq!(squashed.metadatas.len(), 1);
+        // only the metadata and the manifest are new, the chunks are reused
+        assert_eq!(fs::read_dir(image.blob_path())?.count(), blobs + 2);
+        let layer = image.open_metadata_blob(&squashed.metadatas[0].try_into()?, None)?;
+        let inodes = layer.get_inode_vector()?;
+        assert_eq!(inodes.len(), 4);
+        for inode in inodes.iter() {
+            assert!(!matches!(Inode::from_capnp(inode)?.mode, InodeMode::Wht));
+        }
+
+        assert_eq!(walk(&image_dir, "squashed")?, walk(&image_dir, "delta")?);
+        Ok(())
+    }
+}
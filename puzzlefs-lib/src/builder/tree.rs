@@ -0,0 +1,354 @@
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use log::warn;
+use walkdir::WalkDir;
+
+use super::exclude::Excludes;
+use super::filesystem::RangeReader;
+use super::BuildOptions;
+use crate::format::{DirList, FileChunk, Ino, Inode, InodeAdditional, InodeMode, Result, Timespec};
+
+pub(crate) type NodeId = usize;
+
+pub(crate) const ROOT: NodeId = 0;
+
+const DEFAULT_DIR_PERMISSIONS: u16 = 0o755;
+
+fn walker(rootfs: &Path) -> WalkDir {
+    // breadth first search for sharing, order by file name. we only return directories here, so
+    // we can more easily do delta generation to detect what's missing in an existing puzzlefs.
+    // filesystem boundaries are handled by Tree::from_dir, which warns about the mount points it
+    // doesn't walk
+    WalkDir::new(rootfs)
+        .contents_first(false)
+        .follow_links(false)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+}
+
+// where the contents of a regular file are read from when the tree is rendered
+#[derive(Clone)]
+pub(crate) enum Content {
+    // a sparse file uses fewer blocks than its size requires, so it may have holes
+    Host { path: PathBuf, sparse: bool },
+    Spool { offset: u64 },
+    // the chunks of a file of an image, which are reused as they are
+    Image { chunks: Vec<FileChunk> },
+}
+
+pub(crate) struct Node {
+    // the inode as it will be rendered, except for its ino, its directory entries and its file
+    // chunks, which are only known once the whole layer has been built
+    pub(crate) inode: Inode,
+    pub(crate) entries: BTreeMap<OsString, NodeId>,
+    pub(crate) content: Option<Content>,
+    pub(crate) size: u64,
+}
+
+impl Node {
+    pub(crate) fn new(inode: Inode) -> Self {
+        Node {
+            inode,
+            entries: BTreeMap::new(),
+            content: None,
+            size: 0,
+        }
+    }
+
+    pub(crate) fn new_file(inode: Inode, content: Content, size: u64) -> Self {
+        Node {
+            inode,
+            entries: BTreeMap::new(),
+            content: Some(content),
+            size,
+        }
+    }
+
+    pub(crate) fn is_dir(&self) -> bool {
+        matches!(self.inode.mode, InodeMode::Dir { .. })
+    }
+}
+
+/// An in-memory description of a root filesystem, independent of where it was read from.
+///
+/// Nodes are never freed: unlinking a name only removes the directory entry, so nodes that are
+/// still reachable through a hard link elsewhere keep working. Regular files that don't live on
+/// the host are copied into an anonymous spool file.
+pub(crate) struct Tree {
+    nodes: Vec<Node>,
+    spool: Option<Arc<fs::File>>,
+    spool_len: u64,
+    // the inode numbers of the nodes when the tree was last rendered as a layer, if it was
+    rendered: Option<HashMap<NodeId, Ino>>,
+}
+
+impl Tree {
+    pub(crate) fn new() -> Self {
+        Tree {
+            nodes: vec![Node::new(default_dir())],
+            spool: None,
+            spool_len: 0,
+            rendered: None,
+        }
+    }
+
+    // excluded paths are left out of the tree, and excluded directories aren't even walked. unless
+    // told otherwise, the directories on other filesystems than rootfs, i.e. mount points, are
+    // empty directories in the tree
+    pub(crate) fn from_dir(
+        rootfs: &Path,
+        excludes: &Excludes,
+        options: &BuildOptions,
+    ) -> Result<Self> {
+        let root_metadata = fs::symlink_metadata(rootfs)?;
+        let root_additional = InodeAdditional::new(rootfs, &root_metadata)?;
+        let mut tree = Tree::new();
+        tree.nodes[ROOT].inode =
+            Inode::new_dir(0, &root_metadata, empty_dir_list(), root_additional)?;
+
+        // host (device, inode) to node mapping, for hard link detection and for finding the node
+        // of each directory we walk
+        let mut host_to_node = HashMap::from([((root_metadata.dev(), root_metadata.ino()), ROOT)]);
+
+        let rootfs_dirs = walker(rootfs).into_iter().filter_entry(|de| {
+            if de.depth() == 0 {
+                return true;
+            }
+            // the error is returned when the entry is read
+            let Ok(md) = de.metadata() else {
+                return true;
+            };
+            if !md.is_dir() || excludes.is_excluded(relative(rootfs, de.path()), true) {
+                return false;
+            }
+            if md.dev() != root_metadata.dev() && !options.cross_filesystems {
+                warn!(
+                    "leaving out the contents of {}, which is on another filesystem",
+                    de.path().display()
+                );
+                return false;
+            }
+            true
+        });
+
+        for dir in rootfs_dirs {
+            let d = dir.map_err(io::Error::from)?;
+            let dir_metadata = fs::symlink_metadata(d.path())?;
+            let parent = *host_to_node
+                .get(&(dir_metadata.dev(), dir_metadata.ino()))
+                .ok_or_else(|| io::Error::other(format!("no node for {}", d.path().display())))?;
+
+            for e in fs::read_dir(d.path())? {
+                let e = e?;
+                let md = e.metadata()?;
+                if excludes.is_excluded(relative(rootfs, &e.path()), md.is_dir()) {
+                    continue;
+                }
+                options.cancellation.check()?;
+                options.notify(|o| o.file_walked(&e.path()));
+                let node = match host_to_node.get(&(md.dev(), md.ino())) {
+                    Some(&node) => node,
+                    None => {
+                        let path = e.path();
+                        let additional = InodeAdditional::new(&path, &md)?;
+                        let node = if md.is_dir() {
+                            Node::new(Inode::new_dir(0, &md, empty_dir_list(), additional)?)
+                        } else if md.is_file() {
+                            let inode = Inode::new_file(0, &md, Vec::new(), additional)?;
+                            let sparse = md.blocks() * 512 < md.len();
+                            let content = Content::Host { path, sparse };
+                            Node::new_file(inode, content, md.len())
+                        } else {
+                            Node::new(Inode::new_other(0, &md, additional)?)
+                        };
+                        let node = tree.add_node(node);
+                        host_to_node.insert((md.dev(), md.ino()), node);
+                        node
+                    }
+                };
+                tree.nodes[parent].entries.insert(e.file_name(), node);
+            }
+        }
+
+        Ok(tree)
+    }
+
+    pub(crate) fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+
+    pub(crate) fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id]
+    }
+
+    pub(crate) fn add_node(&mut self, node: Node) -> NodeId {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    pub(crate) fn spool(&self) -> Option<&Arc<fs::File>> {
+        self.spool.as_ref()
+    }
+
+    // copies the contents of reader at the end of the spool file, returning where they were
+    // stored and how many bytes were copied
+    pub(crate) fn spool_content(
+        &mut self,
+        reader: &mut impl io::Read,
+    ) -> io::Result<(Content, u64)> {
+        let spool = match &self.spool {
+            Some(spool) => spool,
+            None => self.spool.insert(Arc::new(tempfile::tempfile()?)),
+        };
+        let offset = self.spool_len;
+        let len = io::copy(reader, &mut spool.as_ref())?;
+        self.spool_len += len;
+        Ok((Content::Spool { offset }, len))
+    }
+
+    // opens the contents of a regular file
+    pub(crate) fn open_content(&self, id: NodeId) -> io::Result<Box<dyn io::Read>> {
+        let node = &self.nodes[id];
+        match &node.content {
+            Some(Content::Host { path, .. }) => Ok(Box::new(fs::File::open(path)?)),
+            Some(Content::Spool { offset }) => {
+                // spooled content implies the spool exists
+                let spool = Arc::clone(self.spool.as_ref().unwrap());
+                Ok(Box::new(RangeReader::new(spool, *offset, node.size)))
+            }
+            Some(Content::Image { .. }) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the contents are in an image",
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a regular file",
+            )),
+        }
+    }
+
+    // the link count of each reachable node: the number of names of a file, or 2 plus the number
+    // of subdirectories of a directory
+    pub(crate) fn link_counts(&self) -> HashMap<NodeId, u32> {
+        let mut counts = HashMap::from([(ROOT, 2)]);
+        let mut dirs = vec![ROOT];
+        while let Some(dir) = dirs.pop() {
+            for &child in self.nodes[dir].entries.values() {
+                if self.nodes[child].is_dir() {
+                    // directories are always in counts by the time they are walked
+                    *counts.get_mut(&dir).unwrap() += 1;
+                    // tar archives can hard link directories, only walk them once
+                    if let Entry::Vacant(e) = counts.entry(child) {
+                        e.insert(2);
+                        dirs.push(child);
+                    }
+                } else {
+                    *counts.entry(child).or_default() += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    // removes the excluded entries, for trees that weren't filtered while they were read
+    pub(crate) fn prune(&mut self, excludes: &Excludes) {
+        let mut dirs = vec![(ROOT, PathBuf::new())];
+        while let Some((dir, path)) = dirs.pop() {
+            let mut excluded = Vec::new();
+            for (name, &child) in &self.nodes[dir].entries {
+                let child_path = path.join(name);
+                let is_dir = self.nodes[child].is_dir();
+                if excludes.is_excluded(&child_path, is_dir) {
+                    excluded.push(name.clone());
+                } else if is_dir {
+                    dirs.push((child, child_path));
+                }
+            }
+            for name in excluded {
+                self.unlink(dir, &name);
+            }
+        }
+    }
+
+    pub(crate) fn rendered(&self) -> Option<&HashMap<NodeId, Ino>> {
+        self.rendered.as_ref()
+    }
+
+    pub(crate) fn set_rendered(&mut self, inos: HashMap<NodeId, Ino>) {
+        self.rendered = Some(inos);
+    }
+
+    // returns the node at path, without following symlinks
+    pub(crate) fn lookup(&self, path: &Path) -> io::Result<Option<NodeId>> {
+        let mut cur = ROOT;
+        for name in components(path)? {
+            match self.nodes[cur].entries.get(&name) {
+                Some(&next) => cur = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cur))
+    }
+
+    pub(crate) fn link(&mut self, parent: NodeId, name: &OsStr, node: NodeId) {
+        self.nodes[parent].entries.insert(name.to_os_string(), node);
+    }
+
+    pub(crate) fn unlink(&mut self, parent: NodeId, name: &OsStr) -> Option<NodeId> {
+        self.nodes[parent].entries.remove(name)
+    }
+}
+
+fn relative<'a>(rootfs: &Path, path: &'a Path) -> &'a Path {
+    // everything we walk is below rootfs
+    path.strip_prefix(rootfs).unwrap_or(path)
+}
+
+pub(crate) fn empty_dir_list() -> DirList {
+    DirList {
+        entries: Vec::new(),
+        look_below: false,
+    }
+}
+
+// directories that are only implied by the paths of their children are owned by root
+pub(crate) fn default_dir() -> Inode {
+    Inode {
+        ino: 0,
+        mode: InodeMode::Dir {
+            dir_list: empty_dir_list(),
+        },
+        uid: 0,
+        gid: 0,
+        permissions: DEFAULT_DIR_PERMISSIONS,
+        atime: Timespec::default(),
+        mtime: Timespec::default(),
+        ctime: Timespec::default(),
+        nlink: 1,
+        additional: None,
+    }
+}
+
+// splits a path relative to the root of the tree into its names, rejecting anything that would
+// escape the tree
+pub(crate) fn components(path: &Path) -> io::Result<Vec<OsString>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_os_string()),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid path {}", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(names)
+}
@@ -0,0 +1,362 @@
+use std::any::Any;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::archive::mkdir_all;
+use super::exclude::Excludes;
+use super::tree::{components, empty_dir_list, Node, NodeId, Tree, ROOT};
+use super::{add_tree_delta, initial_tree, BuildOptions, BuildReport};
+use crate::compression::Compression;
+use crate::format::{Inode, InodeAdditional, InodeMode, Result, Timespec, Xattr};
+use crate::oci::{Descriptor, Image};
+
+/// The metadata of an entry added to a [TreeBuilder].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMetadata {
+    pub uid: u32,
+    pub gid: u32,
+    /// The permission bits, including the setuid, setgid and sticky bits.
+    pub permissions: u16,
+    pub mtime: Timespec,
+    pub xattrs: Vec<Xattr>,
+}
+
+impl EntryMetadata {
+    /// Root owned, with the given permissions, a zero mtime and no xattrs.
+    pub fn new(permissions: u16) -> Self {
+        EntryMetadata {
+            uid: 0,
+            gid: 0,
+            permissions,
+            mtime: Timespec::default(),
+            xattrs: Vec::new(),
+        }
+    }
+
+    fn inode(&self, mode: InodeMode, symlink_target: Option<Vec<u8>>) -> Inode {
+        let additional = if symlink_target.is_none() && self.xattrs.is_empty() {
+            None
+        } else {
+            Some(InodeAdditional {
+                xattrs: self.xattrs.clone(),
+                symlink_target,
+            })
+        };
+        Inode {
+            ino: 0,
+            mode,
+            uid: self.uid,
+            gid: self.gid,
+            permissions: self.permissions & 0o7777,
+            // same as for the host filesystem, see Inode::new_inode
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            nlink: 1,
+            additional,
+        }
+    }
+}
+
+/// Builds a puzzlefs image from entries added one by one, e.g. generated files, instead of from a
+/// root filesystem on disk. Paths are relative to the root of the image; like in a tar archive,
+/// an entry replaces whatever was at its path, except for directories, which keep their entries,
+/// and missing parent directories are created, owned by root.
+///
+/// The tree goes through the same pipeline as a root filesystem on disk, [BuildOptions]
+/// included.
+pub struct TreeBuilder {
+    tree: Tree,
+}
+
+impl Default for TreeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TreeBuilder {
+    /// An empty tree, whose root directory is owned by root with 0755 permissions.
+    pub fn new() -> Self {
+        TreeBuilder { tree: Tree::new() }
+    }
+
+    /// Adds a directory, or changes the metadata of an existing one, including the root
+    /// directory.
+    pub fn dir(&mut self, path: impl AsRef<Path>, metadata: &EntryMetadata) -> Result<()> {
+        let inode = metadata.inode(
+            InodeMode::Dir {
+                dir_list: empty_dir_list(),
+            },
+            None,
+        );
+        if components(path.as_ref())?.is_empty() {
+            self.tree.node_mut(ROOT).inode = inode;
+            return Ok(());
+        }
+        let (parent, name) = self.parent(path.as_ref())?;
+        let node = match self.tree.node(parent).entries.get(&name) {
+            Some(&existing) if self.tree.node(existing).is_dir() => {
+                self.tree.node_mut(existing).inode = inode;
+                existing
+            }
+            _ => self.tree.add_node(Node::new(inode)),
+        };
+        self.tree.link(parent, &name, node);
+        Ok(())
+    }
+
+    /// Adds a regular file with the contents of `contents`, which is read to the end; a `&[u8]`
+    /// adds a file from bytes.
+    pub fn file(
+        &mut self,
+        path: impl AsRef<Path>,
+        metadata: &EntryMetadata,
+        mut contents: impl Read,
+    ) -> Result<()> {
+        let inode = metadata.inode(InodeMode::File { chunks: Vec::new() }, None);
+        let (content, size) = self.tree.spool_content(&mut contents)?;
+        self.add(path.as_ref(), Node::new_file(inode, content, size))
+    }
+
+    pub fn symlink(
+        &mut self,
+        path: impl AsRef<Path>,
+        metadata: &EntryMetadata,
+        target: impl AsRef<Path>,
+    ) -> Result<()> {
+        let target = target.as_ref().as_os_str().as_bytes().to_vec();
+        let inode = metadata.inode(InodeMode::Lnk, Some(target));
+        self.add(path.as_ref(), Node::new(inode))
+    }
+
+    pub fn char_device(
+        &mut self,
+        path: impl AsRef<Path>,
+        metadata: &EntryMetadata,
+        major: u64,
+        minor: u64,
+    ) -> Result<()> {
+        let inode = metadata.inode(InodeMode::Chr { major, minor }, None);
+        self.add(path.as_ref(), Node::new(inode))
+    }
+
+    pub fn block_device(
+        &mut self,
+        path: impl AsRef<Path>,
+        metadata: &EntryMetadata,
+        major: u64,
+        minor: u64,
+    ) -> Result<()> {
+        let inode = metadata.inode(InodeMode::Blk { major, minor }, None);
+        self.add(path.as_ref(), Node::new(inode))
+    }
+
+    pub fn fifo(&mut self, path: impl AsRef<Path>, metadata: &EntryMetadata) -> Result<()> {
+        let inode = metadata.inode(InodeMode::Fifo, None);
+        self.add(path.as_ref(), Node::new(inode))
+    }
+
+    /// Adds another name for the existing entry at `target`, which can't be a directory.
+    pub fn hard_link(&mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<()> {
+        let target = target.as_ref();
+        let node = self
+            .tree
+            .lookup(target)?
+            .filter(|&node| !self.tree.node(node).is_dir())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no file to hard link at {}", target.display()),
+                )
+            })?;
+        let (parent, name) = self.parent(path.as_ref())?;
+        self.tree.link(parent, &name, node);
+        Ok(())
+    }
+
+    /// Sets an extended attribute of the existing entry at `path`, replacing its previous value.
+    pub fn set_xattr(&mut self, path: impl AsRef<Path>, key: &[u8], val: &[u8]) -> Result<()> {
+        let path = path.as_ref();
+        let node = self.tree.lookup(path)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no entry at {}", path.display()),
+            )
+        })?;
+        let additional = self
+            .tree
+            .node_mut(node)
+            .inode
+            .additional
+            .get_or_insert_with(|| InodeAdditional {
+                xattrs: Vec::new(),
+                symlink_target: None,
+            });
+        additional.xattrs.retain(|xattr| xattr.key != key);
+        additional.xattrs.push(Xattr {
+            key: key.to_vec(),
+            val: val.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Like [super::build_initial_rootfs_with_report], with the root filesystem built so far.
+    pub fn build_initial_rootfs<C: Compression + Any>(
+        mut self,
+        oci: &Image,
+        options: &BuildOptions,
+    ) -> Result<(Descriptor, BuildReport)> {
+        self.tree.prune(&Excludes::new(None, &options.exclude)?);
+        initial_tree::<C>(&mut self.tree, oci, options)
+    }
+
+    /// Like [super::add_rootfs_delta_with_report], with the root filesystem built so far.
+    pub fn add_rootfs_delta<C: Compression + Any>(
+        mut self,
+        oci: Image,
+        tag: &str,
+        options: &BuildOptions,
+    ) -> Result<(Descriptor, Arc<Image>, BuildReport)> {
+        self.tree.prune(&Excludes::new(None, &options.exclude)?);
+        add_tree_delta::<C>(&mut self.tree, oci, tag, options)
+    }
+
+    fn parent(&mut self, path: &Path) -> Result<(NodeId, OsString)> {
+        let mut names = components(path)?;
+        let name = names.pop().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is the root directory", path.display()),
+            )
+        })?;
+        Ok((
+            mkdir_all(&mut self.tree, &names, &mut HashSet::new())?,
+            name,
+        ))
+    }
+
+    fn add(&mut self, path: &Path, node: Node) -> Result<()> {
+        let (parent, name) = self.parent(path)?;
+        let node = self.tree.add_node(node);
+        self.tree.link(parent, &name, node);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    use crate::compression::Zstd;
+    use crate::reader::{FileReader, PuzzleFS};
+
+    #[test]
+    fn test_tree_builder() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let image = Image::new(dir.path())?;
+        let owned = |uid, permissions| EntryMetadata {
+            uid,
+            gid: uid,
+            mtime: Timespec::new(1000, 5),
+            ..EntryMetadata::new(permissions)
+        };
+
+        let mut tree = TreeBuilder::new();
+        tree.dir("/", &owned(0, 0o700))?;
+        tree.dir("/etc", &owned(0, 0o755))?;
+        tree.file("etc/hostname", &owned(0, 0o644), &b"puzzle"[..])?;
+        tree.file(
+            "srv/app/run",
+            &owned(1000, 0o4755),
+            io::repeat(1).take(100_000),
+        )?;
+        tree.hard_link("srv/app/again", "srv/app/run")?;
+        tree.symlink(
+            "etc/localtime",
+            &owned(0, 0o777),
+            "../usr/share/zoneinfo/UTC",
+        )?;
+        tree.char_device("dev/null", &owned(0, 0o666), 1, 3)?;
+        tree.block_device("dev/sda", &owned(0, 0o660), 8, 0)?;
+        tree.fifo("run/fifo", &owned(0, 0o600))?;
+        tree.file("tmp/cache.log", &owned(0, 0o644), &b"excluded"[..])?;
+        tree.set_xattr("etc/hostname", b"user.origin", b"generated")?;
+        assert!(tree.hard_link("etc2", "etc").is_err());
+        assert!(tree.set_xattr("missing", b"user.a", b"").is_err());
+        assert!(tree
+            .file("etc/hostname/x", &owned(0, 0o644), &b""[..])
+            .is_err());
+
+        let options = BuildOptions {
+            exclude: vec!["*.log".to_string()],
+            ..BuildOptions::default()
+        };
+        let (desc, _) = tree.build_initial_rootfs::<Zstd>(&image, &options)?;
+        image.add_tag("tree", desc)?;
+
+        let pfs = PuzzleFS::open(Image::open(dir.path())?, "tree", None)?;
+        let lookup = |path: &str| pfs.lookup(Path::new(path)).unwrap();
+        let read = |path: &str| -> anyhow::Result<Vec<u8>> {
+            let mut data = Vec::new();
+            FileReader::new(&pfs.oci, &lookup(path).unwrap())?.read_to_end(&mut data)?;
+            Ok(data)
+        };
+
+        assert_eq!(lookup("/").unwrap().permissions, 0o700);
+        let hostname = lookup("/etc/hostname").unwrap();
+        assert_eq!(read("/etc/hostname")?, b"puzzle");
+        assert_eq!(hostname.mtime, Timespec::new(1000, 5));
+        assert_eq!(
+            hostname.additional.unwrap().xattrs,
+            [Xattr {
+                key: b"user.origin".to_vec(),
+                val: b"generated".to_vec()
+            }]
+        );
+
+        let run = lookup("/srv/app/run").unwrap();
+        assert_eq!((run.uid, run.gid, run.permissions), (1000, 1000, 0o4755));
+        assert_eq!(run.nlink, 2);
+        assert_eq!(lookup("/srv/app/again").unwrap().ino, run.ino);
+        assert_eq!(read("/srv/app/run")?, vec![1; 100_000]);
+        // implied directories are owned by root
+        assert_eq!(lookup("/srv").unwrap().permissions, 0o755);
+
+        assert_eq!(
+            lookup("/etc/localtime").unwrap().symlink_target()?,
+            "../usr/share/zoneinfo/UTC"
+        );
+        assert_eq!(
+            lookup("/dev/null").unwrap().mode,
+            InodeMode::Chr { major: 1, minor: 3 }
+        );
+        assert_eq!(
+            lookup("/dev/sda").unwrap().mode,
+            InodeMode::Blk { major: 8, minor: 0 }
+        );
+        assert_eq!(lookup("/run/fifo").unwrap().mode, InodeMode::Fifo);
+        assert!(lookup("/tmp/cache.log").is_none());
+        assert!(lookup("/tmp").is_some());
+
+        // a delta replaces the whole tree
+        let mut tree = TreeBuilder::new();
+        tree.file("etc/hostname", &owned(0, 0o644), &b"delta"[..])?;
+        let (desc, image, _) =
+            tree.add_rootfs_delta::<Zstd>(Image::open(dir.path())?, "tree", &options)?;
+        image.add_tag("delta", desc)?;
+        let pfs = PuzzleFS::open(Image::open(dir.path())?, "delta", None)?;
+        let hostname = pfs.lookup(Path::new("/etc/hostname"))?.unwrap();
+        let mut data = Vec::new();
+        FileReader::new(&pfs.oci, &hostname)?.read_to_end(&mut data)?;
+        assert_eq!(data, b"delta");
+        assert!(pfs.lookup(Path::new("/srv"))?.is_none());
+        Ok(())
+    }
+}
@@ -3,3 +3,7 @@
 pub const MIN_CHUNK_SIZE: u32 = 16 * 1024;
 pub const AVG_CHUNK_SIZE: u32 = 64 * 1024;
 pub const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+// layers whose metadata is larger than this are split into several blobs, so that a lookup only
+// needs to map the part of the metadata it is interested in
+pub const MAX_METADATA_SHARD_SIZE: u64 = 4 * 1024 * 1024;
@@ -0,0 +1,331 @@
+use crate::format::{Ino, Inode, InodeMode, Timespec};
+use crate::oci::Image;
+use crate::reader::{PuzzleFS, WalkPuzzleFS};
+use log::warn;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tar::{Builder, EntryType, Header};
+
+const PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+// the largest values of the octal fields of ustar headers; larger ones are also stored as pax
+// records, since not all readers understand the binary encoding the tar crate falls back to
+const USTAR_MAX_ID: u64 = 0o7777777;
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+// the length of the name field of ustar headers, over which paths may need a pax record
+const USTAR_NAME_LEN: usize = 100;
+
+// the records of a pax extended header, which override the fields of the header that follows it,
+// see https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html#tag_20_92_13_03
+#[derive(Default)]
+struct PaxRecords(Vec<u8>);
+
+impl PaxRecords {
+    fn push(&mut self, key: &[u8], value: &[u8]) {
+        // "<length> <key>=<value>\n", where the length counts its own digits
+        let rest = key.len() + value.len() + 3;
+        let mut len = rest + 1;
+        while rest + len.to_string().len() != len {
+            len = rest + len.to_string().len();
+        }
+        self.0.extend_from_slice(len.to_string().as_bytes());
+        self.0.push(b' ');
+        self.0.extend_from_slice(key);
+        self.0.push(b'=');
+        self.0.extend_from_slice(value);
+        self.0.push(b'\n');
+    }
+
+    fn append_to<W: Write>(&self, archive: &mut Builder<W>) -> io::Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::XHeader);
+        header.set_path("@PaxHeader")?;
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_size(self.0.len() as u64);
+        header.set_cksum();
+        archive.append(&header, self.0.as_slice())
+    }
+}
+
+// decimal seconds since the epoch, where the fraction has the same sign as the seconds
+fn pax_time(t: Timespec) -> String {
+    if t.sec < 0 && t.nsec > 0 {
+        format!("-{}.{:09}", -(t.sec + 1), 1_000_000_000 - t.nsec)
+    } else {
+        format!("{}.{:09}", t.sec, t.nsec)
+    }
+}
+
+fn set_path(header: &mut Header, pax: &mut PaxRecords, path: &[u8]) -> io::Result<()> {
+    let Err(e) = header.set_path(Path::new(OsStr::from_bytes(path))) else {
+        return Ok(());
+    };
+    if path.len() <= USTAR_NAME_LEN {
+        return Err(e);
+    }
+    // readers that don't know pax get the start of the path
+    if let Some(ustar) = header.as_ustar_mut() {
+        ustar.prefix.fill(0);
+    }
+    header
+        .as_old_mut()
+        .name
+        .copy_from_slice(&path[..USTAR_NAME_LEN]);
+    pax.push(b"path", path);
+    Ok(())
+}
+
+fn set_link_name(header: &mut Header, pax: &mut PaxRecords, target: &[u8]) -> io::Result<()> {
+    let Err(e) = header.set_link_name_literal(target) else {
+        return Ok(());
+    };
+    if target.len() <= USTAR_NAME_LEN {
+        return Err(e);
+    }
+    header
+        .as_old_mut()
+        .linkname
+        .copy_from_slice(&target[..USTAR_NAME_LEN]);
+    pax.push(b"linkpath", target);
+    Ok(())
+}
+
+fn set_metadata(header: &mut Header, pax: &mut PaxRecords, inode: &Inode) {
+    header.set_mode(inode.permissions.into());
+    header.set_uid(inode.uid.into());
+    header.set_gid(inode.gid.into());
+    if u64::from(inode.uid) > USTAR_MAX_ID {
+        pax.push(b"uid", inode.uid.to_string().as_bytes());
+    }
+    if u64::from(inode.gid) > USTAR_MAX_ID {
+        pax.push(b"gid", inode.gid.to_string().as_bytes());
+    }
+    header.set_mtime(inode.mtime.sec.max(0) as u64);
+    if inode.mtime.sec < 0 || inode.mtime.nsec != 0 {
+        pax.push(b"mtime", pax_time(inode.mtime).as_bytes());
+    }
+    if let Some(additional) = &inode.additional {
+        for xattr in &additional.xattrs {
+            pax.push(&[PAX_XATTR_PREFIX, &xattr.key].concat(), &xattr.val);
+        }
+    }
+}
+
+/// Writes the root filesystem of a tag to `writer` as a POSIX (pax) tar archive, e.g. to import
+/// it with other tools. Everything comes from the metadata of the image, so this doesn't need any
+/// privileges: the owners, permissions, xattrs (as `SCHILY.xattr.` pax records), device nodes,
+/// symlinks and hard links of the files are kept. The holes of sparse files are written as zeros
+/// and sockets are left out, since tar archives can't have them.
+pub fn export_tar<W: Write>(oci_dir: &str, tag: &str, writer: W) -> anyhow::Result<()> {
+    let image = Image::open(Path::new(oci_dir))?;
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    let mut archive = Builder::new(writer);
+    // the paths in the archive of the inodes that were written, for hard links
+    let mut paths = HashMap::<Ino, Vec<u8>>::new();
+
+    for dir_entry in WalkPuzzleFS::walk(&mut pfs)? {
+        let dir_entry = dir_entry?;
+        let inode = &dir_entry.inode;
+        // the paths in the archive are relative, directories end with a slash
+        let mut path = dir_entry
+            .path
+            .strip_prefix("/")?
+            .as_os_str()
+            .as_bytes()
+            .to_vec();
+        if path.is_empty() {
+            path = b"./".to_vec();
+        } else if let InodeMode::Dir { .. } = inode.mode {
+            path.push(b'/');
+        }
+
+        let mut header = Header::new_ustar();
+        let mut pax = PaxRecords::default();
+        set_path(&mut header, &mut pax, &path)?;
+
+        if let Some(target) = paths.get(&inode.ino) {
+            header.set_entry_type(EntryType::Link);
+            header.set_size(0);
+            set_metadata(&mut header, &mut pax, inode);
+            set_link_name(&mut header, &mut pax, target)?;
+            header.set_cksum();
+            pax.append_to(&mut archive)?;
+            archive.append(&header, io::empty())?;
+            continue;
+        }
+
+        let entry_type = match inode.mode {
+            InodeMode::File { .. } => EntryType::Regular,
+            InodeMode::Dir { .. } => EntryType::Directory,
+            InodeMode::Fifo => EntryType::Fifo,
+            InodeMode::Chr { major, minor } => {
+                header.set_device_major(major.try_into()?)?;
+                header.set_device_minor(minor.try_into()?)?;
+                EntryType::Char
+            }
+            InodeMode::Blk { major, minor } => {
+                header.set_device_major(major.try_into()?)?;
+                header.set_device_minor(minor.try_into()?)?;
+                EntryType::Block
+            }
+            InodeMode::Lnk => {
+                set_link_name(&mut header, &mut pax, inode.symlink_target()?.as_bytes())?;
+                EntryType::Symlink
+            }
+            // whiteouts only hide the files of the layers below, which the walk already leaves out
+            InodeMode::Wht => continue,
+            _ => {
+                warn!(
+                    "skipping {} with unsupported mode {:?}",
+                    dir_entry.path.display(),
+                    inode.mode
+                );
+                continue;
+            }
+        };
+        header.set_entry_type(entry_type);
+        set_metadata(&mut header, &mut pax, inode);
+
+        let size = match inode.mode {
+            InodeMode::File { .. } => inode.file_len()?,
+            _ => 0,
+        };
+        header.set_size(size);
+        if size > USTAR_MAX_SIZE {
+            pax.push(b"size", size.to_string().as_bytes());
+        }
+        header.set_cksum();
+        pax.append_to(&mut archive)?;
+        if size > 0 {
+            archive.append(&header, dir_entry.open()?)?;
+        } else {
+            archive.append(&header, io::empty())?;
+        }
+        if entry_type != EntryType::Directory {
+            paths.insert(inode.ino, path);
+        }
+    }
+
+    archive.into_inner()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use std::collections::BTreeMap;
+    use std::fs::{self, File, FileTimes, Permissions};
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+    use nix::unistd::{chown, mkfifo, Uid};
+    use tar::Archive;
+
+    use crate::builder::build_test_fs;
+
+    use super::*;
+
+    #[test]
+    fn test_export_tar() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let long_name = "x".repeat(150);
+
+        fs::create_dir_all(rootfs.join("d")).unwrap();
+        fs::write(rootfs.join("d/f"), b"contents").unwrap();
+        xattr::set(rootfs.join("d/f"), "user.meshuggah", b"rocks").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+        File::open(rootfs.join("d/f"))
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(mtime))
+            .unwrap();
+        fs::hard_link(rootfs.join("d/f"), rootfs.join("d/g")).unwrap();
+        fs::set_permissions(rootfs.join("d"), Permissions::from_mode(0o750)).unwrap();
+        std::os::unix::fs::symlink("d/f", rootfs.join("s")).unwrap();
+        mkfifo(&rootfs.join("p"), Mode::S_IRUSR).unwrap();
+        fs::write(rootfs.join(&long_name), b"long").unwrap();
+        let privileged = Uid::effective().is_root();
+        if privileged {
+            let null = rootfs.join("null");
+            mknod(&null, SFlag::S_IFCHR, Mode::S_IRUSR, makedev(1, 3)).unwrap();
+            chown(&rootfs.join("d/f"), Some(Uid::from_raw(3_000_000)), None).unwrap();
+        }
+
+        let rootfs_desc = build_test_fs(&rootfs, &image).unwrap();
+        image.add_tag("test", rootfs_desc).unwrap();
+
+        let mut tar = Vec::new();
+        export_tar(oci_dir.to_str().unwrap(), "test", &mut tar).unwrap();
+
+        let mut entries = BTreeMap::new();
+        for entry in Archive::new(tar.as_slice()).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_string();
+            let mut pax = BTreeMap::new();
+            if let Some(extensions) = entry.pax_extensions().unwrap() {
+                for extension in extensions {
+                    let extension = extension.unwrap();
+                    let key = extension.key().unwrap().to_string();
+                    pax.insert(key, extension.value_bytes().to_vec());
+                }
+            }
+            let header = entry.header().clone();
+            let link_name = entry
+                .link_name()
+                .unwrap()
+                .map(|l| l.to_str().unwrap().to_string());
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            entries.insert(path, (header, pax, link_name, data));
+        }
+
+        let mut expected = vec!["./", "d/", "d/f", "d/g", "p", "s", &long_name];
+        if privileged {
+            expected.push("null");
+        }
+        expected.sort();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), expected);
+
+        let (header, _, _, _) = &entries["d/"];
+        assert_eq!(header.entry_type(), EntryType::Directory);
+        assert_eq!(header.mode().unwrap(), 0o750);
+
+        let (header, pax, _, data) = &entries["d/f"];
+        assert_eq!(header.entry_type(), EntryType::Regular);
+        assert_eq!(data, b"contents");
+        assert_eq!(pax["SCHILY.xattr.user.meshuggah"], b"rocks");
+        assert_eq!(pax["mtime"], b"1000000000.123456789");
+        if privileged {
+            assert_eq!(pax["uid"], b"3000000");
+        }
+
+        let (header, _, link_name, _) = &entries["d/g"];
+        assert_eq!(header.entry_type(), EntryType::Link);
+        assert_eq!(link_name.as_deref(), Some("d/f"));
+
+        let (header, _, link_name, _) = &entries["s"];
+        assert_eq!(header.entry_type(), EntryType::Symlink);
+        assert_eq!(link_name.as_deref(), Some("d/f"));
+
+        assert_eq!(entries["p"].0.entry_type(), EntryType::Fifo);
+        assert_eq!(entries[&long_name].3, b"long");
+
+        if privileged {
+            let (header, _, _, _) = &entries["null"];
+            assert_eq!(header.entry_type(), EntryType::Char);
+            assert_eq!(header.device_major().unwrap(), Some(1));
+            assert_eq!(header.device_minor().unwrap(), Some(3));
+        }
+    }
+}
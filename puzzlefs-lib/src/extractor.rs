@@ -1,21 +1,39 @@
-use crate::format::InodeMode;
+use crate::format::{Inode, InodeMode, Timespec};
 use crate::oci::Image;
-use crate::reader::{PuzzleFS, WalkPuzzleFS};
+use crate::reader::{DirEntry, PuzzleFS, WalkPuzzleFS};
+use globset::GlobBuilder;
 use log::info;
-use nix::sys::stat::{makedev, mknod, Mode, SFlag};
-use nix::unistd::{chown, mkfifo, symlinkat, Gid, Uid};
+use nix::sys::stat::{makedev, mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{fchownat, mkfifo, symlinkat, FchownatFlags, Gid, Uid};
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::Permissions;
+use std::io::{Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Component, Path, PathBuf};
-use std::{fs, io};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{fs, io, thread};
 
 fn runs_privileged() -> bool {
     Uid::effective().is_root()
 }
 
+fn set_times(path: &Path, atime: Timespec, mtime: Timespec) -> nix::Result<()> {
+    let to_timespec = |t: Timespec| TimeSpec::new(t.sec, t.nsec.into());
+    // don't follow symlinks, we want the timestamps of the link itself
+    utimensat(
+        None,
+        path,
+        &to_timespec(atime),
+        &to_timespec(mtime),
+        UtimensatFlags::NoFollowSymlink,
+    )
+}
+
 fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
     // need to be a bit careful here about paths in the case of malicious images so we don't write
     // things outside where we're supposed to. Bad cases are paths like "/../../.." or images
@@ -66,83 +84,319 @@ fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
     Ok(buf)
 }
 
-pub fn extract_rootfs(oci_dir: &str, tag: &str, extract_dir: &str) -> anyhow::Result<()> {
-    let oci_dir = Path::new(oci_dir);
-    let image = Image::open(oci_dir)?;
-    let dir = Path::new(extract_dir);
-    fs::create_dir_all(dir)?;
-    let mut pfs = PuzzleFS::open(image, tag, None)?;
-    let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
+/// Options for extracting a puzzlefs image.
+#[derive(Debug, Default, Clone)]
+pub struct ExtractOptions {
+    /// Writes the contents of the files and sets the metadata of the inodes with a pool of worker
+    /// threads, while the image is walked: the walk creates the inodes and their hard links in
+    /// order and hands them to the workers. The result is the same as extracting the files one
+    /// at a time.
+    pub parallel: bool,
+    /// Only extracts these paths of the image, with everything below them and their parent
+    /// directories. They are absolute paths or glob patterns, e.g. `/usr/bin/*sh` or
+    /// `/etc/**/*.conf`, where `*` doesn't match `/`. Every one of them has to match a path of the
+    /// image. By default, the whole image is extracted.
+    pub paths: Vec<String>,
+}
+
+// creates the inode of an entry; files are created empty and filled in by write_contents
+fn create_inode(path: &Path, dir_entry: &DirEntry) -> anyhow::Result<()> {
+    let mode = Mode::from_bits_truncate(dir_entry.inode.permissions.into());
+    match dir_entry.inode.mode {
+        InodeMode::File { .. } => {
+            fs::File::create(path)?;
+        }
+        InodeMode::Dir { .. } => fs::create_dir_all(path)?,
+        InodeMode::Fifo => {
+            mkfifo(path, mode)?;
+        }
+        InodeMode::Chr { major, minor } => {
+            mknod(path, SFlag::S_IFCHR, mode, makedev(major, minor))?;
+        }
+        InodeMode::Blk { major, minor } => {
+            mknod(path, SFlag::S_IFBLK, mode, makedev(major, minor))?;
+        }
+        InodeMode::Lnk => {
+            let target = dir_entry.inode.symlink_target()?;
+            symlinkat(target, None, path)?;
+        }
+        // a socket file without a listener, like the ones left behind by a process that
+        // exited without removing them
+        InodeMode::Sock => {
+            mknod(path, SFlag::S_IFSOCK, mode, 0)?;
+        }
+        _ => {
+            bail!("bad inode mode {:#?}", dir_entry.inode.mode)
+        }
+    }
+    Ok(())
+}
+
+fn write_contents(path: &Path, dir_entry: &DirEntry) -> anyhow::Result<()> {
+    let InodeMode::File { ref chunks } = dir_entry.inode.mode else {
+        return Ok(());
+    };
+    let mut reader = dir_entry.open()?;
+    let mut f = fs::OpenOptions::new().write(true).open(path)?;
+    // seek over the holes of sparse files rather than writing zeros, so that they are holes in
+    // the extracted files as well
+    let mut offset = 0;
+    for chunk in chunks {
+        if chunk.blob.is_some() {
+            reader.seek(SeekFrom::Start(offset))?;
+            f.seek(SeekFrom::Start(offset))?;
+            io::copy(&mut (&mut reader).take(chunk.len), &mut f)?;
+        }
+        offset += chunk.len;
+    }
+    f.set_len(offset)?;
+    Ok(())
+}
+
+// sets everything but the permissions and timestamps of directories, which are set once their
+// children have been extracted
+fn set_metadata(path: &Path, inode: &Inode) -> anyhow::Result<()> {
+    // changing the owner clears the setuid and setgid bits and the security.capability xattr, so
+    // it comes before both. For symlinks it changes the owner of the link itself
+    if runs_privileged() {
+        fchownat(
+            None,
+            path,
+            Some(Uid::from_raw(inode.uid)),
+            Some(Gid::from_raw(inode.gid)),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+    }
+
+    if let Some(x) = &inode.additional {
+        for x in &x.xattrs {
+            xattr::set(path, OsStr::from_bytes(&x.key), &x.val)?;
+        }
+    }
+
+    if let InodeMode::Dir { .. } = inode.mode {
+        return Ok(());
+    }
+
+    // trying to change permissions for a symlink would follow the symlink and we might not have extracted the target yet
+    // anyway, symlink permissions are not used in Linux (although they are used in macOS and FreeBSD)
+    if inode.mode != InodeMode::Lnk {
+        std::fs::set_permissions(path, Permissions::from_mode(inode.permissions.into()))?;
+    }
+    set_times(path, inode.atime, inode.mtime)?;
+    Ok(())
+}
+
+fn finish_entry(path: &Path, dir_entry: &DirEntry) -> anyhow::Result<()> {
+    write_contents(path, dir_entry)?;
+    set_metadata(path, &dir_entry.inode)
+}
+
+// a directory whose permissions and timestamps are set once its children have been extracted,
+// since creating them changes its mtime and its permissions may not allow it
+struct ExtractedDir {
+    path: PathBuf,
+    permissions: u16,
+    atime: Timespec,
+    mtime: Timespec,
+}
+
+// creates the inodes of the selected paths and their hard links in the order of the walk, and
+// passes them to `finish` to fill them in; returns the directories that were created
+fn create_entries(
+    pfs: &mut PuzzleFS,
+    dir: &Path,
+    roots: &[PathBuf],
+    mut finish: impl FnMut(PathBuf, DirEntry) -> anyhow::Result<()>,
+) -> anyhow::Result<Vec<ExtractedDir>> {
     let mut host_to_pfs = HashMap::<crate::format::Ino, PathBuf>::new();
+    let mut dirs = Vec::new();
 
-    walker.try_for_each(|de| -> anyhow::Result<()> {
-        let dir_entry = de?;
+    let mut extract_entry = |dir_entry: DirEntry| -> anyhow::Result<()> {
         let path = safe_path(dir, &dir_entry.path)?;
-        let mut is_symlink = false;
         info!("extracting {:#?}", path);
+        // whiteouts only hide the files of the layers below, which the walk already leaves out
+        if let InodeMode::Wht = dir_entry.inode.mode {
+            return Ok(());
+        }
         if let Some(existing_path) = host_to_pfs.get(&dir_entry.inode.ino) {
+            // directories can't be hard linked, they are the parents of several selected paths
+            if let InodeMode::Dir { .. } = dir_entry.inode.mode {
+                return Ok(());
+            }
             fs::hard_link(existing_path, &path)?;
             return Ok(());
         }
         host_to_pfs.insert(dir_entry.inode.ino, path.clone());
 
-        match dir_entry.inode.mode {
-            InodeMode::File { .. } => {
-                let mut reader = dir_entry.open()?;
-                let mut f = fs::File::create(&path)?;
-                io::copy(&mut reader, &mut f)?;
-            }
-            InodeMode::Dir { .. } => fs::create_dir_all(&path)?,
-            // TODO: fix all the hard coded modes when we have modes
-            InodeMode::Fifo => {
-                mkfifo(&path, Mode::S_IRWXU)?;
-            }
-            InodeMode::Chr { major, minor } => {
-                mknod(&path, SFlag::S_IFCHR, Mode::S_IRWXU, makedev(major, minor))?;
-            }
-            InodeMode::Blk { major, minor } => {
-                mknod(&path, SFlag::S_IFBLK, Mode::S_IRWXU, makedev(major, minor))?;
-            }
-            InodeMode::Lnk => {
-                let target = dir_entry.inode.symlink_target()?;
-                is_symlink = true;
-                symlinkat(target, None, &path)?;
-            }
-            InodeMode::Sock => {
-                todo!();
-            }
-            InodeMode::Wht => {
-                todo!();
+        create_inode(&path, &dir_entry)?;
+        if let InodeMode::Dir { .. } = dir_entry.inode.mode {
+            dirs.push(ExtractedDir {
+                path: path.clone(),
+                permissions: dir_entry.inode.permissions,
+                atime: dir_entry.inode.atime,
+                mtime: dir_entry.inode.mtime,
+            });
+        }
+        finish(path, dir_entry)
+    };
+
+    for root in roots {
+        // the parent directories of a selected path are extracted without their other entries
+        let mut parents = root.ancestors().skip(1).collect::<Vec<_>>();
+        parents.reverse();
+        for parent in parents {
+            let inode = pfs
+                .lookup(parent)?
+                .ok_or_else(|| anyhow!("{} is not in the image", parent.display()))?;
+            extract_entry(DirEntry::new(
+                Arc::clone(&pfs.oci),
+                parent.to_owned(),
+                inode,
+            ))?;
+        }
+        WalkPuzzleFS::walk_from(pfs, root)?.try_for_each(|dir_entry| extract_entry(dir_entry?))?;
+    }
+    Ok(dirs)
+}
+
+// reading and decompressing the chunks is CPU bound, so the entries are finished by a pool of
+// workers while the walk goes on; they don't depend on each other once they have been created
+fn create_entries_parallel(
+    pfs: &mut PuzzleFS,
+    dir: &Path,
+    roots: &[PathBuf],
+) -> anyhow::Result<Vec<ExtractedDir>> {
+    let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+    // bound the number of entries waiting for a worker, so the walk doesn't get ahead of them
+    let (sender, receiver) = mpsc::sync_channel::<(PathBuf, DirEntry)>(workers * 2);
+    // the workers own the receiver, so sending fails once all of them are gone
+    let receiver = Arc::new(Mutex::new(receiver));
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        let handles = (0..workers)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let failed = &failed;
+                s.spawn(move || loop {
+                    // the lock guard is dropped as soon as an entry is received
+                    let job = receiver.lock().unwrap().recv();
+                    let Ok((path, dir_entry)) = job else {
+                        return Ok(());
+                    };
+                    if let Err(e) = finish_entry(&path, &dir_entry) {
+                        // stop the walk at its next entry
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(receiver);
+
+        let created = create_entries(pfs, dir, roots, |path, dir_entry| {
+            if failed.load(Ordering::Relaxed) {
+                bail!("extraction stopped");
             }
-            _ => {
-                bail!("bad inode mode {:#?}", dir_entry.inode.mode)
+            // all the workers are gone, either because they failed or because they panicked, which
+            // joining them propagates
+            sender
+                .send((path, dir_entry))
+                .map_err(|_| anyhow!("extraction stopped"))
+        });
+        // let the workers finish; their errors come first, the walk only stops because of them
+        drop(sender);
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap())?;
+        created
+    })
+}
+
+// the paths of the image selected by the patterns of ExtractOptions::paths, leaving out the ones
+// below other selected paths
+fn select_paths(pfs: &mut PuzzleFS, patterns: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let is_glob = |c: Component<'_>| c.as_os_str().as_bytes().iter().any(|b| b"*?[{".contains(b));
+    let mut selected = Vec::new();
+
+    for pattern in patterns {
+        let pattern = Path::new("/").join(pattern);
+        // the components before the first wildcard are looked up rather than matched
+        let prefix = pattern
+            .components()
+            .take_while(|c| !is_glob(*c))
+            .collect::<PathBuf>();
+        if prefix == pattern {
+            if pfs.lookup(&pattern)?.is_none() {
+                bail!("{} is not in the image", pattern.display());
             }
+            selected.push(pattern);
+            continue;
         }
-        if let Some(x) = dir_entry.inode.additional {
-            for x in &x.xattrs {
-                xattr::set(&path, OsStr::from_bytes(&x.key), &x.val)?;
+
+        let Some(glob) = pattern.to_str() else {
+            bail!("bad glob pattern {}", pattern.display());
+        };
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        let found = selected.len();
+        if pfs.lookup(&prefix)?.is_some() {
+            for dir_entry in WalkPuzzleFS::walk_from(pfs, &prefix)? {
+                let dir_entry = dir_entry?;
+                if matcher.is_match(&dir_entry.path) {
+                    selected.push(dir_entry.path);
+                }
             }
         }
-
-        // trying to change permissions for a symlink would follow the symlink and we might not have extracted the target yet
-        // anyway, symlink permissions are not used in Linux (although they are used in macOS and FreeBSD)
-        if !is_symlink {
-            std::fs::set_permissions(
-                &path,
-                Permissions::from_mode(dir_entry.inode.permissions.into()),
-            )?;
+        if selected.len() == found {
+            bail!("{} doesn't match any path in the image", pattern.display());
         }
+    }
 
-        if runs_privileged() {
-            chown(
-                &path,
-                Some(Uid::from_raw(dir_entry.inode.uid)),
-                Some(Gid::from_raw(dir_entry.inode.gid)),
-            )?;
+    // the paths below a path come right after it once sorted
+    selected.sort();
+    let mut roots = Vec::<PathBuf>::new();
+    for path in selected {
+        if !roots.last().is_some_and(|root| path.starts_with(root)) {
+            roots.push(path);
         }
+    }
+    Ok(roots)
+}
 
-        Ok(())
-    })?;
+pub fn extract_rootfs(
+    oci_dir: &str,
+    tag: &str,
+    extract_dir: &str,
+    options: &ExtractOptions,
+) -> anyhow::Result<()> {
+    let oci_dir = Path::new(oci_dir);
+    let image = Image::open(oci_dir)?;
+    let dir = Path::new(extract_dir);
+    fs::create_dir_all(dir)?;
+    let mut pfs = PuzzleFS::open(image, tag, None)?;
+    let roots = if options.paths.is_empty() {
+        vec![PathBuf::from("/")]
+    } else {
+        select_paths(&mut pfs, &options.paths)?
+    };
+    let dirs = if options.parallel {
+        create_entries_parallel(&mut pfs, dir, &roots)?
+    } else {
+        create_entries(&mut pfs, dir, &roots, |path, dir_entry| {
+            finish_entry(&path, &dir_entry)
+        })?
+    };
+
+    // the children come after their parents in the walk, so they are done first
+    for dir in dirs.iter().rev() {
+        fs::set_permissions(&dir.path, Permissions::from_mode(dir.permissions.into()))?;
+        set_times(&dir.path, dir.atime, dir.mtime)?;
+    }
     Ok(())
 }
 
@@ -150,10 +404,13 @@ pub fn extract_rootfs(oci_dir: &str, tag: &str, extract_dir: &str) -> anyhow::Re
 mod tests {
     use tempfile::{tempdir, TempDir};
 
-    use std::fs::File;
+    use std::fs::{File, FileTimes};
+    use std::time::{Duration, SystemTime};
 
     use crate::builder::build_test_fs;
-    use std::os::unix::fs::MetadataExt;
+    use crate::oci::Digest;
+    use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+    use std::os::unix::net::UnixListener;
     use walkdir::WalkDir;
 
     use super::*;
@@ -195,6 +452,7 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractOptions::default(),
         )
         .unwrap();
 
@@ -246,6 +504,7 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractOptions::default(),
         )
         .unwrap();
 
@@ -256,6 +515,223 @@ mod tests {
         assert_eq!(metadata.permissions().mode() & 0xFFF, TESTED_PERMISSION);
     }
 
+    #[test]
+    fn test_special_files() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let extract_dir = tempdir().unwrap();
+
+        fs::create_dir_all(&rootfs).unwrap();
+        mkfifo(&rootfs.join("fifo"), Mode::from_bits_truncate(0o640)).unwrap();
+        drop(UnixListener::bind(rootfs.join("socket")).unwrap());
+        fs::set_permissions(rootfs.join("socket"), Permissions::from_mode(0o600)).unwrap();
+        fs::write(rootfs.join("target"), b"target").unwrap();
+        std::os::unix::fs::symlink("target", rootfs.join("link")).unwrap();
+        if runs_privileged() {
+            let null = rootfs.join("null");
+            mknod(&null, SFlag::S_IFCHR, Mode::S_IRUSR, makedev(1, 3)).unwrap();
+            fs::set_permissions(&null, Permissions::from_mode(0o620)).unwrap();
+            fchownat(
+                None,
+                &rootfs.join("link"),
+                Some(Uid::from_raw(1000)),
+                Some(Gid::from_raw(1000)),
+                FchownatFlags::NoFollowSymlink,
+            )
+            .unwrap();
+        }
+
+        let rootfs_desc = build_test_fs(&rootfs, &image).unwrap();
+        image.add_tag("test", rootfs_desc).unwrap();
+
+        extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        let md = |name| fs::symlink_metadata(extract_dir.path().join(name)).unwrap();
+        assert!(md("fifo").file_type().is_fifo());
+        assert_eq!(md("fifo").mode() & 0o7777, 0o640);
+        assert!(md("socket").file_type().is_socket());
+        assert_eq!(md("socket").mode() & 0o7777, 0o600);
+        if runs_privileged() {
+            assert!(md("null").file_type().is_char_device());
+            assert_eq!(md("null").rdev(), makedev(1, 3));
+            assert_eq!(md("null").mode() & 0o7777, 0o620);
+            assert_eq!((md("link").uid(), md("link").gid()), (1000, 1000));
+            assert_eq!((md("target").uid(), md("target").gid()), (0, 0));
+        }
+    }
+
+    #[test]
+    fn test_parallel_extraction() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 0);
+        for i in 0..8 {
+            let subdir = rootfs.join(format!("dir{i}"));
+            fs::create_dir_all(&subdir).unwrap();
+            for j in 0..8 {
+                let path = subdir.join(format!("file{j}"));
+                fs::write(&path, format!("{i} {j}").repeat(i * 1000 + j)).unwrap();
+                File::open(&path)
+                    .unwrap()
+                    .set_times(FileTimes::new().set_modified(mtime))
+                    .unwrap();
+            }
+            fs::set_permissions(&subdir, Permissions::from_mode(0o750)).unwrap();
+        }
+        fs::set_permissions(rootfs.join("dir1/file1"), Permissions::from_mode(0o4755)).unwrap();
+        fs::hard_link(rootfs.join("dir1/file1"), rootfs.join("dir2/link")).unwrap();
+        std::os::unix::fs::symlink("../dir1/file1", rootfs.join("dir3/symlink")).unwrap();
+
+        let rootfs_desc = build_test_fs(&rootfs, &image).unwrap();
+        image.add_tag("test", rootfs_desc).unwrap();
+
+        let extract = |parallel| {
+            let extract_dir = tempdir().unwrap();
+            extract_rootfs(
+                oci_dir.to_str().unwrap(),
+                "test",
+                extract_dir.path().to_str().unwrap(),
+                &ExtractOptions {
+                    parallel,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            extract_dir
+        };
+        let list = |extract_dir: &TempDir| {
+            WalkDir::new(extract_dir.path())
+                .sort_by_file_name()
+                .into_iter()
+                .skip(1)
+                .map(|ent| {
+                    let ent = ent.unwrap();
+                    let md = ent.metadata().unwrap();
+                    let contents = md.is_file().then(|| fs::read(ent.path()).unwrap());
+                    let path = ent.path().strip_prefix(extract_dir.path()).unwrap();
+                    let mtime = md.modified().unwrap();
+                    (path.to_owned(), md.mode(), md.nlink(), mtime, contents)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let serial = extract(false);
+        let parallel = extract(true);
+        assert_eq!(list(&serial), list(&parallel));
+        let md = |path| fs::metadata(parallel.path().join(path)).unwrap();
+        assert_eq!(md("dir1/file1").ino(), md("dir2/link").ino());
+        assert_eq!(md("dir1/file1").mode() & 0o7777, 0o4755);
+        assert_eq!(md("dir1").mode() & 0o7777, 0o750);
+
+        // an error of a worker stops the extraction
+        let pfs = PuzzleFS::open(Image::open(&oci_dir).unwrap(), "test", None).unwrap();
+        let inode = pfs.lookup(Path::new("/dir7/file7")).unwrap().unwrap();
+        let InodeMode::File { chunks } = inode.mode else {
+            panic!("bad inode mode: {:?}", inode.mode);
+        };
+        let digest = Digest::new(&chunks[0].blob.unwrap().digest);
+        fs::remove_file(image.blob_path().join(digest.to_string())).unwrap();
+        let extract_dir = tempdir().unwrap();
+        assert!(extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+            &ExtractOptions {
+                parallel: true,
+                ..Default::default()
+            },
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_extract_paths() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+
+        fs::create_dir_all(rootfs.join("etc/sub")).unwrap();
+        fs::create_dir_all(rootfs.join("usr/bin")).unwrap();
+        for path in [
+            "etc/a",
+            "etc/sub/c.conf",
+            "etc/sub/d",
+            "usr/bin/sh",
+            "usr/bin/ls",
+        ] {
+            fs::write(rootfs.join(path), path).unwrap();
+        }
+        fs::hard_link(rootfs.join("usr/bin/sh"), rootfs.join("usr/bin/bash")).unwrap();
+        fs::set_permissions(rootfs.join("etc"), Permissions::from_mode(0o700)).unwrap();
+
+        let rootfs_desc = build_test_fs(&rootfs, &image).unwrap();
+        image.add_tag("test", rootfs_desc).unwrap();
+
+        let extract = |paths: &[&str]| {
+            let extract_dir = tempdir().unwrap();
+            let options = ExtractOptions {
+                paths: paths.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            };
+            extract_rootfs(
+                oci_dir.to_str().unwrap(),
+                "test",
+                extract_dir.path().to_str().unwrap(),
+                &options,
+            )
+            .map(|_| extract_dir)
+        };
+        let list = |extract_dir: &TempDir| {
+            WalkDir::new(extract_dir.path())
+                .sort_by_file_name()
+                .into_iter()
+                .skip(1)
+                .map(|ent| {
+                    let ent = ent.unwrap();
+                    let path = ent.path().strip_prefix(extract_dir.path()).unwrap();
+                    path.to_str().unwrap().to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let extract_dir = extract(&["/etc/sub", "usr/bin/*sh", "/etc/sub/*.conf"]).unwrap();
+        assert_eq!(
+            list(&extract_dir),
+            [
+                "etc",
+                "etc/sub",
+                "etc/sub/c.conf",
+                "etc/sub/d",
+                "usr",
+                "usr/bin",
+                "usr/bin/bash",
+                "usr/bin/sh"
+            ]
+        );
+        let md = |path| fs::metadata(extract_dir.path().join(path)).unwrap();
+        assert_eq!(md("usr/bin/sh").ino(), md("usr/bin/bash").ino());
+        assert_eq!(md("etc").mode() & 0o7777, 0o700);
+
+        // a glob doesn't match across directories
+        let extract_dir = extract(&["/*/a"]).unwrap();
+        assert_eq!(list(&extract_dir), ["etc", "etc/a"]);
+
+        extract(&["/etc/missing"]).unwrap_err();
+        extract(&["/etc/*.missing"]).unwrap_err();
+    }
+
     #[test]
     fn test_hardlink_extraction() {
         let dir = tempdir().unwrap();
@@ -285,6 +761,7 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractOptions::default(),
         )
         .unwrap();
 
@@ -316,9 +793,93 @@ mod tests {
             oci_dir.to_str().unwrap(),
             "test",
             extract_dir.path().to_str().unwrap(),
+            &ExtractOptions::default(),
         )
         .unwrap();
         let extracted_foo = extract_dir.path().join("foo");
         assert_eq!(extracted_foo.metadata().unwrap().len(), 0);
     }
+
+    #[test]
+    fn test_timestamps() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let extract_dir = tempdir().unwrap();
+
+        let foo = rootfs.join("foo");
+        let bar = foo.join("bar");
+
+        fs::create_dir_all(&foo).unwrap();
+        fs::write(&bar, b"bar").unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+        for path in [&bar, &foo] {
+            File::open(path)
+                .unwrap()
+                .set_times(FileTimes::new().set_modified(mtime))
+                .unwrap();
+        }
+
+        let rootfs_desc = build_test_fs(&rootfs, &image).unwrap();
+        image.add_tag("test", rootfs_desc).unwrap();
+
+        extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+
+        for path in ["foo", "foo/bar"] {
+            let md = fs::symlink_metadata(extract_dir.path().join(path)).unwrap();
+            assert_eq!(md.modified().unwrap(), mtime);
+        }
+    }
+
+    #[test]
+    fn test_sparse_file() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        let extract_dir = tempdir().unwrap();
+
+        fs::create_dir_all(&rootfs).unwrap();
+        let sparse = File::create(rootfs.join("sparse")).unwrap();
+        sparse.set_len(8 << 20).unwrap();
+        sparse.write_all_at(b"data", 4 << 20).unwrap();
+        sparse.sync_all().unwrap();
+
+        let rootfs_desc = build_test_fs(&rootfs, &image).unwrap();
+        image.add_tag("test", rootfs_desc).unwrap();
+
+        let pfs = PuzzleFS::open(Image::open(&oci_dir).unwrap(), "test", None).unwrap();
+        let inode = pfs.lookup(Path::new("/sparse")).unwrap().unwrap();
+        let InodeMode::File { chunks } = inode.mode else {
+            panic!("bad inode mode: {:?}", inode.mode);
+        };
+        assert_eq!(chunks.first().unwrap().blob, None);
+        assert_eq!(chunks.last().unwrap().blob, None);
+        let data = chunks.iter().filter(|c| c.blob.is_some()).map(|c| c.len);
+        assert!(data.sum::<u64>() < 1 << 20);
+
+        extract_rootfs(
+            oci_dir.to_str().unwrap(),
+            "test",
+            extract_dir.path().to_str().unwrap(),
+            &ExtractOptions::default(),
+        )
+        .unwrap();
+        let extracted = extract_dir.path().join("sparse");
+        let md = extracted.metadata().unwrap();
+        assert_eq!(md.len(), 8 << 20);
+        assert!(md.blocks() * 512 < 1 << 20);
+        assert_eq!(
+            fs::read(extracted).unwrap(),
+            fs::read(rootfs.join("sparse")).unwrap()
+        );
+    }
 }
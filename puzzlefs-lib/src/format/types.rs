@@ -1,4 +1,7 @@
 use capnp::{message, serialize};
+use fastcdc::v2020::{
+    AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
 use memmap2::{Mmap, MmapOptions};
 use nix::errno::Errno;
 use nix::sys::stat;
@@ -14,12 +17,15 @@ use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::de::Error as SerdeError;
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use super::error::{Result, WireFormatError};
+use crate::common::{AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
 use hex::FromHexError;
 
 pub const DEFAULT_FILE_PERMISSIONS: u16 = 0o644;
@@ -33,6 +39,12 @@ pub struct Rootfs {
     pub metadatas: Vec<BlobRef>,
     pub fs_verity_data: VerityData,
     pub manifest_version: u64,
+    // the chunking parameters of the most recent layer, which deltas should reuse so they share
+    // chunks with the layers below
+    pub chunk_params: ChunkParams,
+    // how the metadata of the most recent layer was normalized, if it was, which deltas should
+    // reuse so that the image stays reproducible
+    pub canonicalization: Option<Canonicalization>,
 }
 
 impl Rootfs {
@@ -59,10 +71,27 @@ impl Rootfs {
             fs_verity_data.insert(digest, verity);
         }
 
+        // images built before the chunking parameters were recorded all used the defaults
+        let chunk_params = if reader.has_chunk_params() {
+            ChunkParams::from_capnp(reader.get_chunk_params()?)?
+        } else {
+            ChunkParams::default()
+        };
+
+        let canonicalization = if reader.has_canonicalization() {
+            Some(Canonicalization::from_capnp(
+                reader.get_canonicalization()?,
+            )?)
+        } else {
+            None
+        };
+
         Ok(Rootfs {
             metadatas: metadata_vec,
             fs_verity_data,
             manifest_version: reader.get_manifest_version(),
+            chunk_params,
+            canonicalization,
         })
     }
 
@@ -91,10 +120,289 @@ impl Rootfs {
             capnp_verity.set_verity(verity);
         }
 
+        self.chunk_params
+            .fill_capnp(&mut builder.reborrow().init_chunk_params());
+
+        if let Some(canonicalization) = &self.canonicalization {
+            canonicalization.fill_capnp(&mut builder.reborrow().init_canonicalization())?;
+        }
+
         Ok(())
     }
 }
 
+/// The algorithm used to split the contents of the regular files of a layer into chunks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkAlgorithm {
+    /// FastCDC content defined chunking, see https://www.usenix.org/conference/atc16/technical-sessions/presentation/xia
+    #[default]
+    FastCdc,
+    /// Chunks of exactly the average size, except for the last one.
+    Fixed,
+    /// Content defined chunking with a buzhash rolling hash, like casync.
+    Buzhash,
+}
+
+impl ChunkAlgorithm {
+    fn from_capnp(algorithm: crate::manifest_capnp::ChunkAlgorithm) -> Self {
+        match algorithm {
+            crate::manifest_capnp::ChunkAlgorithm::Fastcdc => ChunkAlgorithm::FastCdc,
+            crate::manifest_capnp::ChunkAlgorithm::Fixed => ChunkAlgorithm::Fixed,
+            crate::manifest_capnp::ChunkAlgorithm::Buzhash => ChunkAlgorithm::Buzhash,
+        }
+    }
+
+    fn to_capnp(self) -> crate::manifest_capnp::ChunkAlgorithm {
+        match self {
+            ChunkAlgorithm::FastCdc => crate::manifest_capnp::ChunkAlgorithm::Fastcdc,
+            ChunkAlgorithm::Fixed => crate::manifest_capnp::ChunkAlgorithm::Fixed,
+            ChunkAlgorithm::Buzhash => crate::manifest_capnp::ChunkAlgorithm::Buzhash,
+        }
+    }
+}
+
+impl fmt::Display for ChunkAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ChunkAlgorithm::FastCdc => "fastcdc",
+            ChunkAlgorithm::Fixed => "fixed",
+            ChunkAlgorithm::Buzhash => "buzhash",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for ChunkAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fastcdc" => Ok(ChunkAlgorithm::FastCdc),
+            "fixed" => Ok(ChunkAlgorithm::Fixed),
+            "buzhash" => Ok(ChunkAlgorithm::Buzhash),
+            _ => Err(format!(
+                "unknown chunking algorithm {s}, expected one of fastcdc, fixed, buzhash"
+            )),
+        }
+    }
+}
+
+/// How the regular files of a layer are split into chunks; sizes are in bytes. Fixed size
+/// chunking only uses the average size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkParams {
+    pub algorithm: ChunkAlgorithm,
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        ChunkParams {
+            algorithm: ChunkAlgorithm::default(),
+            min_size: MIN_CHUNK_SIZE,
+            avg_size: AVG_CHUNK_SIZE,
+            max_size: MAX_CHUNK_SIZE,
+        }
+    }
+}
+
+impl ChunkParams {
+    pub fn from_capnp(reader: crate::manifest_capnp::chunk_params::Reader<'_>) -> Result<Self> {
+        let algorithm = match reader.get_algorithm() {
+            Ok(algorithm) => ChunkAlgorithm::from_capnp(algorithm),
+            Err(::capnp::NotInSchema(_e)) => {
+                return Err(WireFormatError::InvalidSerializedData(Backtrace::capture()))
+            }
+        };
+        Ok(ChunkParams {
+            algorithm,
+            min_size: reader.get_min_size(),
+            avg_size: reader.get_avg_size(),
+            max_size: reader.get_max_size(),
+        })
+    }
+
+    pub fn fill_capnp(&self, builder: &mut crate::manifest_capnp::chunk_params::Builder<'_>) {
+        builder.set_algorithm(self.algorithm.to_capnp());
+        builder.set_min_size(self.min_size);
+        builder.set_avg_size(self.avg_size);
+        builder.set_max_size(self.max_size);
+    }
+
+    // the chunkers only support sizes in the ranges of FastCDC, which are large enough for the
+    // window of the rolling hash
+    pub fn validate(&self) -> io::Result<()> {
+        let check = |name, size, min, max| {
+            if (min..=max).contains(&size) {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{name} chunk size {size} is not between {min} and {max}"),
+                ))
+            }
+        };
+        check("average", self.avg_size, AVERAGE_MIN, AVERAGE_MAX)?;
+        if self.algorithm == ChunkAlgorithm::Fixed {
+            return Ok(());
+        }
+        check("minimum", self.min_size, MINIMUM_MIN, MINIMUM_MAX)?;
+        check("maximum", self.max_size, MAXIMUM_MIN, MAXIMUM_MAX)?;
+        if self.min_size > self.avg_size || self.avg_size > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "chunk sizes must be ordered, got minimum {}, average {} and maximum {}",
+                    self.min_size, self.avg_size, self.max_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What happens to the timestamps of the inodes of a canonical image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    /// The timestamps of the root filesystem are kept.
+    Keep,
+    /// All the timestamps are the epoch.
+    Zero,
+    /// The timestamps later than the given number of seconds since the epoch are set to it, see
+    /// https://reproducible-builds.org/docs/source-date-epoch/
+    Clamp(i64),
+}
+
+impl TimestampPolicy {
+    /// Clamps the timestamps to `SOURCE_DATE_EPOCH` when it is set, and zeroes them otherwise.
+    pub fn from_env() -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => epoch
+                .trim()
+                .parse()
+                .map(TimestampPolicy::Clamp)
+                .map_err(|e| invalid(format!("invalid SOURCE_DATE_EPOCH {epoch}: {e}"))),
+            Err(std::env::VarError::NotPresent) => Ok(TimestampPolicy::Zero),
+            Err(e) => Err(invalid(format!("invalid SOURCE_DATE_EPOCH: {e}"))),
+        }
+    }
+}
+
+/// How the metadata of the inodes is normalized, so that the same files give the same image
+/// whichever host they are built on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Canonicalization {
+    pub timestamps: TimestampPolicy,
+    /// The keys of the xattrs that aren't stored, e.g. security labels that depend on the host.
+    pub dropped_xattrs: Vec<Vec<u8>>,
+    /// Root owns all the files, except for the ones the build gives a fixed owner.
+    pub normalize_ownership: bool,
+    /// Directories and executables have 0755 permissions, symlinks 0777 and everything else 0644.
+    /// The setuid, setgid and sticky bits are kept, since the files don't work the same without
+    /// them.
+    pub normalize_permissions: bool,
+}
+
+impl Default for Canonicalization {
+    fn default() -> Self {
+        Canonicalization {
+            timestamps: TimestampPolicy::Zero,
+            dropped_xattrs: vec![b"security.selinux".to_vec()],
+            normalize_ownership: false,
+            normalize_permissions: false,
+        }
+    }
+}
+
+impl Canonicalization {
+    pub fn from_capnp(reader: crate::manifest_capnp::canonicalization::Reader<'_>) -> Result<Self> {
+        use crate::manifest_capnp::canonicalization::timestamps;
+        let timestamps = match reader.get_timestamps().which() {
+            Ok(timestamps::Keep(())) => TimestampPolicy::Keep,
+            Ok(timestamps::Zero(())) => TimestampPolicy::Zero,
+            Ok(timestamps::Clamp(sec)) => TimestampPolicy::Clamp(sec),
+            Err(::capnp::NotInSchema(_e)) => {
+                return Err(WireFormatError::InvalidSerializedData(Backtrace::capture()))
+            }
+        };
+        let dropped_xattrs = reader
+            .get_dropped_xattrs()?
+            .iter()
+            .map(|key| key.map(<[u8]>::to_vec))
+            .collect::<capnp::Result<_>>()?;
+        Ok(Canonicalization {
+            timestamps,
+            dropped_xattrs,
+            normalize_ownership: reader.get_normalize_ownership(),
+            normalize_permissions: reader.get_normalize_permissions(),
+        })
+    }
+
+    pub fn fill_capnp(
+        &self,
+        builder: &mut crate::manifest_capnp::canonicalization::Builder<'_>,
+    ) -> Result<()> {
+        let mut timestamps = builder.reborrow().init_timestamps();
+        match self.timestamps {
+            TimestampPolicy::Keep => timestamps.set_keep(()),
+            TimestampPolicy::Zero => timestamps.set_zero(()),
+            TimestampPolicy::Clamp(sec) => timestamps.set_clamp(sec),
+        }
+
+        let len = self.dropped_xattrs.len().try_into()?;
+        let mut dropped_xattrs = builder.reborrow().init_dropped_xattrs(len);
+        for (i, key) in self.dropped_xattrs.iter().enumerate() {
+            // we already checked that the length of dropped_xattrs fits inside a u32
+            dropped_xattrs.set(i as u32, key);
+        }
+
+        builder.set_normalize_ownership(self.normalize_ownership);
+        builder.set_normalize_permissions(self.normalize_permissions);
+        Ok(())
+    }
+
+    // normalizes everything but the owner of the inode, which the builder takes care of since it
+    // may be overridden
+    pub fn apply(&self, inode: &mut Inode) {
+        let time = match self.timestamps {
+            TimestampPolicy::Keep => None,
+            TimestampPolicy::Zero => Some(Timespec::default()),
+            TimestampPolicy::Clamp(sec) if inode.mtime.sec >= sec => Some(Timespec::new(sec, 0)),
+            TimestampPolicy::Clamp(_) => Some(inode.mtime),
+        };
+        // the access and change times depend on the history of the files on the host, only
+        // their contents are reproducible
+        if let Some(time) = time {
+            inode.atime = time;
+            inode.mtime = time;
+            inode.ctime = time;
+        }
+
+        if let Some(additional) = &mut inode.additional {
+            additional
+                .xattrs
+                .retain(|xattr| !self.dropped_xattrs.contains(&xattr.key));
+            if additional.xattrs.is_empty() && additional.symlink_target.is_none() {
+                inode.additional = None;
+            }
+        }
+
+        if self.normalize_permissions {
+            let special = inode.permissions & 0o7000;
+            inode.permissions = special
+                | match inode.mode {
+                    InodeMode::Dir { .. } => 0o755,
+                    InodeMode::Lnk => 0o777,
+                    InodeMode::File { .. } if inode.permissions & 0o111 != 0 => 0o755,
+                    _ => DEFAULT_FILE_PERMISSIONS,
+                };
+        }
+    }
+}
+
 // TODO: should this be an ociv1 digest and include size and media type?
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BlobRef {
@@ -119,27 +427,47 @@ impl BlobRef {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataShard {
+    pub blob: BlobRef,
+    pub first_ino: Ino,
+    pub last_ino: Ino,
+}
+
+impl MetadataShard {
+    pub fn from_capnp(reader: crate::metadata_capnp::metadata_shard::Reader<'_>) -> Result<Self> {
+        Ok(MetadataShard {
+            blob: BlobRef::from_capnp(reader.get_blob()?)?,
+            first_ino: reader.get_first_ino(),
+            last_ino: reader.get_last_ino(),
+        })
+    }
+
+    pub fn fill_capnp(&self, builder: &mut crate::metadata_capnp::metadata_shard::Builder<'_>) {
+        self.blob.fill_capnp(&mut builder.reborrow().init_blob());
+        builder.set_first_ino(self.first_ino);
+        builder.set_last_ino(self.last_ino);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DirEnt {
     pub ino: Ino,
     pub name: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DirList {
     // TODO: flags instead?
     pub look_below: bool,
     pub entries: Vec<DirEnt>,
 }
 
-#[derive(Debug)]
-pub struct FileChunkList {
-    pub chunks: Vec<FileChunk>,
-}
-
-#[derive(Debug, PartialEq, Eq)]
+/// A part of the contents of a regular file. A chunk without a blob is a hole in a sparse file,
+/// which reads as `len` zeros.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileChunk {
-    pub blob: BlobRef,
+    pub blob: Option<BlobRef>,
     pub len: u64,
 }
 
@@ -148,7 +476,11 @@ pub type Ino = u64;
 impl FileChunk {
     pub fn from_capnp(reader: crate::metadata_capnp::file_chunk::Reader<'_>) -> Result<Self> {
         let len = reader.get_len();
-        let blob = BlobRef::from_capnp(reader.get_blob()?)?;
+        let blob = if reader.has_blob() {
+            Some(BlobRef::from_capnp(reader.get_blob()?)?)
+        } else {
+            None
+        };
 
         Ok(FileChunk { blob, len })
     }
@@ -208,6 +540,10 @@ mod tests {
                 uid: 0,
                 gid: 0,
                 permissions: 0,
+                atime: Timespec::default(),
+                mtime: Timespec::default(),
+                ctime: Timespec::default(),
+                nlink: 1,
                 additional: None,
             },
             Inode {
@@ -216,27 +552,43 @@ mod tests {
                 uid: 0,
                 gid: 0,
                 permissions: 0,
+                atime: Timespec::default(),
+                mtime: Timespec::default(),
+                ctime: Timespec::default(),
+                nlink: 1,
                 additional: None,
             },
             Inode {
                 ino: 0,
                 mode: InodeMode::File {
-                    chunks: vec![FileChunk {
-                        blob: BlobRef {
-                            digest: [
-                                0x12, 0x44, 0xFE, 0xDD, 0x13, 0x39, 0x88, 0x12, 0x48, 0xA8, 0xF8,
-                                0xE4, 0x22, 0x12, 0x15, 0x16, 0x12, 0x44, 0xFE, 0xDD, 0x31, 0x93,
-                                0x88, 0x21, 0x84, 0x8A, 0xF8, 0x4E, 0x22, 0x12, 0x51, 0x16,
-                            ],
-                            offset: 100,
-                            compressed: true,
+                    chunks: vec![
+                        FileChunk {
+                            blob: Some(BlobRef {
+                                digest: [
+                                    0x12, 0x44, 0xFE, 0xDD, 0x13, 0x39, 0x88, 0x12, 0x48, 0xA8,
+                                    0xF8, 0xE4, 0x22, 0x12, 0x15, 0x16, 0x12, 0x44, 0xFE, 0xDD,
+                                    0x31, 0x93, 0x88, 0x21, 0x84, 0x8A, 0xF8, 0x4E, 0x22, 0x12,
+                                    0x51, 0x16,
+                                ],
+                                offset: 100,
+                                compressed: true,
+                            }),
+                            len: 100,
                         },
-                        len: 100,
-                    }],
+                        // a hole
+                        FileChunk {
+                            blob: None,
+                            len: 4096,
+                        },
+                    ],
                 },
                 uid: 0,
                 gid: 0,
                 permissions: DEFAULT_FILE_PERMISSIONS,
+                atime: Timespec::default(),
+                mtime: Timespec::default(),
+                ctime: Timespec::default(),
+                nlink: 1,
                 additional: None,
             },
             Inode {
@@ -248,6 +600,10 @@ mod tests {
                 uid: 10,
                 gid: 10000,
                 permissions: DEFAULT_DIRECTORY_PERMISSIONS,
+                atime: Timespec::new(1700000000, 0),
+                mtime: Timespec::new(1700000000, 123456789),
+                ctime: Timespec::new(-1, 999999999),
+                nlink: 3,
                 additional: None,
             },
             Inode {
@@ -256,6 +612,10 @@ mod tests {
                 uid: 0,
                 gid: 0,
                 permissions: 0xFFFF,
+                atime: Timespec::default(),
+                mtime: Timespec::default(),
+                ctime: Timespec::default(),
+                nlink: 1,
                 additional: Some(InodeAdditional {
                     xattrs: vec![Xattr {
                         key: b"some extended attribute".to_vec(),
@@ -280,16 +640,93 @@ mod tests {
             assert_eq!(test, after);
         }
     }
+
+    #[test]
+    fn test_canonicalization() {
+        let inode = Inode {
+            ino: 2,
+            mode: InodeMode::File { chunks: Vec::new() },
+            uid: 1000,
+            gid: 1000,
+            permissions: 0o4710,
+            atime: Timespec::new(300, 0),
+            mtime: Timespec::new(200, 5),
+            ctime: Timespec::new(250, 0),
+            nlink: 1,
+            additional: Some(InodeAdditional {
+                xattrs: vec![Xattr {
+                    key: b"security.selinux".to_vec(),
+                    val: b"system_u:object_r:tmp_t:s0".to_vec(),
+                }],
+                symlink_target: None,
+            }),
+        };
+        let canonical = |policy: &Canonicalization| {
+            let mut inode = inode.clone();
+            policy.apply(&mut inode);
+            inode
+        };
+
+        let zeroed = canonical(&Canonicalization::default());
+        assert_eq!(zeroed.mtime, Timespec::default());
+        assert_eq!(zeroed.atime, Timespec::default());
+        assert_eq!(zeroed.ctime, Timespec::default());
+        assert_eq!(zeroed.additional, None);
+        assert_eq!((zeroed.uid, zeroed.permissions), (1000, 0o4710));
+
+        for (sec, expected) in [(100, Timespec::new(100, 0)), (1000, inode.mtime)] {
+            let clamped = canonical(&Canonicalization {
+                timestamps: TimestampPolicy::Clamp(sec),
+                dropped_xattrs: Vec::new(),
+                normalize_permissions: true,
+                ..Canonicalization::default()
+            });
+            assert_eq!(clamped.mtime, expected);
+            assert_eq!(clamped.atime, expected);
+            assert_eq!(clamped.ctime, expected);
+            assert_eq!(clamped.additional, inode.additional);
+            assert_eq!(clamped.permissions, 0o4755);
+        }
+
+        // a world writable directory keeps its sticky bit
+        let mut tmp = Inode {
+            mode: InodeMode::Dir {
+                dir_list: DirList {
+                    look_below: false,
+                    entries: Vec::new(),
+                },
+            },
+            permissions: 0o1777,
+            additional: None,
+            ..inode.clone()
+        };
+        let policy = Canonicalization {
+            normalize_permissions: true,
+            ..Canonicalization::default()
+        };
+        policy.apply(&mut tmp);
+        assert_eq!(tmp.permissions, 0o1755);
+        let mut setgid = Inode {
+            permissions: 0o2644,
+            ..inode.clone()
+        };
+        policy.apply(&mut setgid);
+        assert_eq!(setgid.permissions, 0o2644);
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Inode {
     pub ino: Ino,
     pub mode: InodeMode,
     pub uid: u32,
     pub gid: u32,
     pub permissions: u16,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
     pub additional: Option<InodeAdditional>,
+    pub nlink: u32,
 }
 
 impl Inode {
@@ -300,7 +737,11 @@ impl Inode {
             uid: reader.get_uid(),
             gid: reader.get_gid(),
             permissions: reader.get_permissions(),
+            atime: Timespec::from_capnp(reader.get_atime()?),
+            mtime: Timespec::from_capnp(reader.get_mtime()?),
+            ctime: Timespec::from_capnp(reader.get_ctime()?),
             additional: InodeAdditional::from_capnp(reader.get_additional()?)?,
+            nlink: reader.get_nlink(),
         })
     }
 
@@ -317,6 +758,11 @@ impl Inode {
         builder.set_gid(self.gid);
         builder.set_permissions(self.permissions);
 
+        self.atime.fill_capnp(&mut builder.reborrow().init_atime());
+        self.mtime.fill_capnp(&mut builder.reborrow().init_mtime());
+        self.ctime.fill_capnp(&mut builder.reborrow().init_ctime());
+        builder.set_nlink(self.nlink);
+
         if let Some(additional) = &self.additional {
             let mut additional_builder = builder.reborrow().init_additional();
             additional.fill_capnp(&mut additional_builder)?;
@@ -405,6 +851,10 @@ impl Inode {
             uid: 0,
             gid: 0,
             permissions: DEFAULT_FILE_PERMISSIONS,
+            atime: Timespec::default(),
+            mtime: Timespec::default(),
+            ctime: Timespec::default(),
+            nlink: 1,
             additional: None,
         }
     }
@@ -422,6 +872,13 @@ impl Inode {
             gid: md.gid(),
             // only preserve rwx permissions for user, group, others (9 bits) and SUID/SGID/sticky bit (3 bits)
             permissions: (md.permissions().mode() & 0xFFF) as u16,
+            // canonical builds don't keep these, see Canonicalization
+            atime: Timespec::new(md.atime(), md.atime_nsec()),
+            mtime: Timespec::new(md.mtime(), md.mtime_nsec()),
+            ctime: Timespec::new(md.ctime(), md.ctime_nsec()),
+            // the host link count may include names outside of the image, the builder counts the
+            // names in the image instead
+            nlink: 1,
             additional,
         }
     }
@@ -474,7 +931,7 @@ impl Inode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InodeMode {
     Unknown,
     Fifo,
@@ -583,8 +1040,10 @@ impl InodeMode {
                     // we already checked that the length of chunks fits inside a u32
                     let mut chunk_builder = chunks_builder.reborrow().get(i as u32);
                     chunk_builder.set_len(chunk.len);
-                    let mut blob_ref_builder = chunk_builder.init_blob();
-                    chunk.blob.fill_capnp(&mut blob_ref_builder);
+                    if let Some(blob) = &chunk.blob {
+                        let mut blob_ref_builder = chunk_builder.init_blob();
+                        blob.fill_capnp(&mut blob_ref_builder);
+                    }
                 }
             }
             Self::Lnk => builder.set_lnk(()),
@@ -595,7 +1054,47 @@ impl InodeMode {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timespec {
+    pub fn new(sec: i64, nsec: i64) -> Self {
+        Timespec {
+            sec,
+            // the kernel guarantees 0 <= nsec < 1_000_000_000
+            nsec: nsec as u32,
+        }
+    }
+
+    pub fn from_capnp(reader: crate::metadata_capnp::timespec::Reader<'_>) -> Self {
+        Timespec {
+            sec: reader.get_sec(),
+            nsec: reader.get_nsec(),
+        }
+    }
+
+    pub fn fill_capnp(&self, builder: &mut crate::metadata_capnp::timespec::Builder<'_>) {
+        builder.set_sec(self.sec);
+        builder.set_nsec(self.nsec);
+    }
+}
+
+impl From<Timespec> for SystemTime {
+    fn from(t: Timespec) -> Self {
+        let since_epoch = Duration::from_secs(t.sec.unsigned_abs());
+        let base = if t.sec < 0 {
+            UNIX_EPOCH - since_epoch
+        } else {
+            UNIX_EPOCH + since_epoch
+        };
+        base + Duration::from_nanos(t.nsec.into())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InodeAdditional {
     pub xattrs: Vec<Xattr>,
     pub symlink_target: Option<Vec<u8>>,
@@ -680,7 +1179,7 @@ impl InodeAdditional {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Xattr {
     pub key: Vec<u8>,
     pub val: Vec<u8>,
@@ -727,9 +1226,22 @@ impl MetadataBlob {
         self.reader.get()?.get_inodes()
     }
 
+    // the shards of a sharded layer, in which case the blob itself doesn't contain any inodes
+    pub fn shards(&self) -> Result<Vec<MetadataShard>> {
+        self.reader
+            .get()?
+            .get_shards()?
+            .iter()
+            .map(MetadataShard::from_capnp)
+            .collect()
+    }
+
     pub fn find_inode(&self, ino: Ino) -> Result<Option<crate::metadata_capnp::inode::Reader<'_>>> {
         let mut left = 0;
         let inodes = self.get_inode_vector()?;
+        if inodes.is_empty() {
+            return Ok(None);
+        }
         let mut right = inodes.len() - 1;
 
         while left <= right {
@@ -756,6 +1268,9 @@ impl MetadataBlob {
 
     pub fn max_ino(&self) -> Result<Option<Ino>> {
         let inodes = self.get_inode_vector()?;
+        if inodes.is_empty() {
+            return Ok(None);
+        }
         let last_index = inodes.len() - 1;
         Ok(Some(inodes.get(last_index).get_ino()))
     }
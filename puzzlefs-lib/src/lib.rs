@@ -6,6 +6,7 @@ extern crate anyhow;
 pub mod builder;
 mod common;
 pub mod compression;
+pub mod exporter;
 pub mod extractor;
 mod format;
 pub mod fsverity_helpers;
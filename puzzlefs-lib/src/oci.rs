@@ -42,6 +42,9 @@ pub struct Image {
     oci_dir_fd: Dir,
 }
 
+// the descriptor of a blob, the fs-verity digest of its data and whether its data is compressed
+pub type StoredBlob = (Descriptor, [u8; SHA256_BLOCK_SIZE], bool);
+
 impl Image {
     pub fn new(oci_dir: &Path) -> Result<Self> {
         fs::create_dir_all(oci_dir)?;
@@ -85,7 +88,17 @@ impl Image {
     pub fn put_blob<C: Compression + Any, MT: media_types::MediaType>(
         &self,
         buf: &[u8],
-    ) -> Result<(Descriptor, [u8; SHA256_BLOCK_SIZE], bool)> {
+    ) -> Result<StoredBlob> {
+        self.put_blob_deduplicated::<C, MT>(buf)
+            .map(|(stored, ..)| stored)
+    }
+
+    // like put_blob, also returning the size of the blob in the image and whether it was already
+    // there
+    pub(crate) fn put_blob_deduplicated<C: Compression + Any, MT: media_types::MediaType>(
+        &self,
+        buf: &[u8],
+    ) -> Result<(StoredBlob, u64, bool)> {
         let mut compressed_data = Cursor::new(Vec::<u8>::new());
         let mut compressed = C::compress(&mut compressed_data)?;
         let mut hasher = Sha256::new();
@@ -117,7 +130,8 @@ impl Image {
         let path = self.blob_path().join(descriptor.digest.to_string());
 
         // avoid replacing the data blob so we don't drop fsverity data
-        if path.exists() {
+        let exists = path.exists();
+        if exists {
             let mut hasher = Sha256::new();
             let mut file = fs::File::open(path)?;
             io::copy(&mut file, &mut hasher)?;
@@ -135,7 +149,11 @@ impl Image {
             tmp.write_all(final_data)?;
             tmp.persist(path).map_err(|e| e.error)?;
         }
-        Ok((descriptor, fs_verity_digest, compressed_blob))
+        Ok((
+            (descriptor, fs_verity_digest, compressed_blob),
+            final_data.len() as u64,
+            exists,
+        ))
     }
 
     fn open_raw_blob(&self, digest: &Digest, verity: Option<&[u8]>) -> io::Result<fs::File> {
@@ -6,6 +6,7 @@ use crate::format::Result;
 use crate::oci::Image;
 
 mod puzzlefs;
+pub use puzzlefs::FileReader;
 pub use puzzlefs::PuzzleFS;
 pub use puzzlefs::PUZZLEFS_IMAGE_MANIFEST_VERSION;
 
@@ -14,7 +15,7 @@ pub use fuse::Fuse;
 
 mod walk;
 use fuse::PipeDescriptor;
-pub use walk::WalkPuzzleFS;
+pub use walk::{DirEntry, WalkPuzzleFS};
 
 // copied from the fuser function 'MountOption::from_str' because it's not exported
 fn mount_option_from_str(s: &str) -> fuse_ffi::MountOption {
@@ -14,8 +14,8 @@ use std::path::{Path, PathBuf};
 use std::thread;
 
 use fuser::{
-    FileAttr, FileType, Filesystem, KernelConfig, ReplyData, ReplyEntry, ReplyOpen, Request,
-    TimeOrNow,
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyData, ReplyEntry, ReplyLseek, ReplyOpen,
+    Request, TimeOrNow,
 };
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
@@ -23,7 +23,7 @@ use std::time::{Duration, SystemTime};
 
 use crate::format::{DirEnt, Inode, InodeMode, Result, WireFormatError};
 
-use super::puzzlefs::{file_read, PuzzleFS};
+use super::puzzlefs::{file_read, seek_data_or_hole, PuzzleFS};
 
 pub enum PipeDescriptor {
     UnnamedPipe(PipeWriter),
@@ -38,6 +38,40 @@ pub struct Fuse {
     // cache, so for now we just do each lookup every time.
 }
 
+// the preferred I/O size, in which the files are accounted for in st_blocks
+const BLOCK_SIZE: u64 = 4096;
+
+// the number of 512 byte blocks used by a file: the blocks its data is in, its holes don't use any
+fn blocks(inode: &Inode) -> u64 {
+    let InodeMode::File { chunks } = &inode.mode else {
+        return 0;
+    };
+    let mut blocks = 0;
+    // the number of the next block not counted yet
+    let mut next = 0;
+    let mut offset = 0;
+    for chunk in chunks {
+        if chunk.blob.is_some() && chunk.len > 0 {
+            let first = (offset / BLOCK_SIZE).max(next);
+            next = (offset + chunk.len).div_ceil(BLOCK_SIZE);
+            blocks += next.saturating_sub(first);
+        }
+        offset += chunk.len;
+    }
+    blocks * (BLOCK_SIZE / 512)
+}
+
+// the dev_t of device nodes in the kernel's 32 bit encoding (see new_encode_dev() in
+// include/linux/kdev_t.h), which is what fuse expects
+fn rdev(inode: &Inode) -> u32 {
+    match inode.mode {
+        InodeMode::Chr { major, minor } | InodeMode::Blk { major, minor } => {
+            ((minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)) as u32
+        }
+        _ => 0,
+    }
+}
+
 fn mode_to_fuse_type(inode: &Inode) -> Result<FileType> {
     Ok(match inode.mode {
         InodeMode::File { .. } => FileType::RegularFile,
@@ -77,18 +111,19 @@ impl Fuse {
         Ok(FileAttr {
             ino: ic.ino,
             size: len,
-            blocks: 0,
-            atime: SystemTime::UNIX_EPOCH,
-            mtime: SystemTime::UNIX_EPOCH,
-            ctime: SystemTime::UNIX_EPOCH,
+            blocks: blocks(&ic),
+            atime: ic.atime.into(),
+            mtime: ic.mtime.into(),
+            ctime: ic.ctime.into(),
             crtime: SystemTime::UNIX_EPOCH,
             kind,
             perm: ic.permissions,
-            nlink: 0,
+            // images built before link counts were recorded don't know them
+            nlink: ic.nlink.max(1),
             uid: ic.uid,
             gid: ic.gid,
-            rdev: 0,
-            blksize: 0,
+            rdev: rdev(&ic),
+            blksize: BLOCK_SIZE as u32,
             flags: 0,
         })
     }
@@ -124,6 +159,19 @@ impl Fuse {
         Ok(buf)
     }
 
+    // the kernel only forwards SEEK_DATA and SEEK_HOLE, it handles the other kinds of seeks itself
+    fn _lseek(&mut self, ino: u64, offset: i64, whence: i32) -> Result<i64> {
+        let inode = self.pfs.find_inode(ino)?;
+        let data = match whence {
+            nix::libc::SEEK_DATA => true,
+            nix::libc::SEEK_HOLE => false,
+            _ => return Err(WireFormatError::from_errno(Errno::EINVAL)),
+        };
+        let offset =
+            u64::try_from(offset).map_err(|_| WireFormatError::from_errno(Errno::ENXIO))?;
+        Ok(seek_data_or_hole(&inode, offset, data)?.try_into()?)
+    }
+
     fn _readdir(&mut self, ino: u64, offset: i64, reply: &mut fuser::ReplyDirectory) -> Result<()> {
         let inode = self.pfs.find_inode(ino)?;
         let entries = inode.dir_entries()?;
@@ -546,6 +594,24 @@ impl Filesystem for Fuse {
         }
     }
 
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        match self._lseek(ino, offset, whence) {
+            Ok(offset) => reply.offset(offset),
+            Err(e) => {
+                debug!("cannot lseek ino {ino}, offset: {offset}, whence: {whence} {e}!");
+                reply.error(e.to_errno())
+            }
+        }
+    }
+
     fn release(
         &mut self,
         _req: &Request<'_>,
@@ -675,16 +741,65 @@ impl Filesystem for Fuse {
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use super::*;
+
     use std::io;
-    use std::path::Path;
+    use std::os::unix::fs::MetadataExt;
 
     use sha2::{Digest, Sha256};
     use tempfile::tempdir;
 
     use crate::builder::build_test_fs;
+    use crate::format::{BlobRef, FileChunk, Timespec};
     use crate::oci::Image;
 
+    fn inode(mode: InodeMode) -> Inode {
+        Inode {
+            ino: 2,
+            mode,
+            uid: 0,
+            gid: 0,
+            permissions: 0o644,
+            atime: Timespec::default(),
+            mtime: Timespec::default(),
+            ctime: Timespec::default(),
+            additional: None,
+            nlink: 1,
+        }
+    }
+
+    #[test]
+    fn test_blocks_and_rdev() {
+        let data = |len| FileChunk {
+            blob: Some(BlobRef {
+                digest: [0; 32],
+                offset: 0,
+                compressed: false,
+            }),
+            len,
+        };
+        let hole = |len| FileChunk { blob: None, len };
+        let file = |chunks| inode(InodeMode::File { chunks });
+
+        assert_eq!(blocks(&file(vec![])), 0);
+        assert_eq!(blocks(&file(vec![data(1)])), 8);
+        // chunks sharing a block only count it once
+        assert_eq!(blocks(&file(vec![data(100), data(5000)])), 16);
+        assert_eq!(blocks(&file(vec![data(4096), hole(1 << 20), data(10)])), 16);
+        assert_eq!(blocks(&file(vec![hole(1 << 20)])), 0);
+        assert_eq!(blocks(&inode(InodeMode::Lnk)), 0);
+
+        assert_eq!(rdev(&inode(InodeMode::Chr { major: 1, minor: 3 })), 0x103);
+        assert_eq!(
+            rdev(&inode(InodeMode::Blk {
+                major: 259,
+                minor: 0x12345
+            })),
+            0x12310345
+        );
+        assert_eq!(rdev(&inode(InodeMode::Fifo)), 0);
+    }
+
     #[test]
     fn test_fuse() {
         let dir = tempdir().unwrap();
@@ -712,6 +827,14 @@ mod tests {
             Path::new("SekienAkashita.jpg")
         );
 
+        let original = fs::metadata("src/builder/test/test-1/SekienAkashita.jpg").unwrap();
+        let mounted = ents[0].metadata().unwrap();
+        assert_eq!(mounted.modified().unwrap(), original.modified().unwrap());
+        assert_eq!(mounted.nlink(), 1);
+        assert!(mounted.blocks() * 512 >= mounted.len());
+        assert_eq!(mounted.blksize(), BLOCK_SIZE);
+        assert_eq!(fs::metadata(mountpoint.path()).unwrap().nlink(), 2);
+
         let mut hasher = Sha256::new();
         let mut f = fs::File::open(ents[0].path()).unwrap();
         io::copy(&mut f, &mut hasher).unwrap();
@@ -1,18 +1,23 @@
 use nix::errno::Errno;
 use std::backtrace::Backtrace;
 use std::cmp::min;
+use std::collections::BTreeMap;
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Component, Path};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 use crate::compression::Noop;
 use crate::format::{
-    DirEnt, Ino, Inode, InodeMode, MetadataBlob, Result, VerityData, WireFormatError,
+    BlobRef, DirEnt, DirList, Ino, Inode, InodeMode, MetadataBlob, MetadataShard, Result, Rootfs,
+    VerityData, WireFormatError,
 };
+use crate::metadata_capnp;
 use crate::oci::{Digest, Image};
 
-pub const PUZZLEFS_IMAGE_MANIFEST_VERSION: u64 = 2;
+pub const PUZZLEFS_IMAGE_MANIFEST_VERSION: u64 = 4;
+// version 3 added sharded metadata and version 4 sparse files, older images can still be read
+const OLDEST_SUPPORTED_MANIFEST_VERSION: u64 = 2;
 
 pub(crate) fn file_read(
     oci: &Image,
@@ -58,12 +63,19 @@ pub(crate) fn file_read(
         file_offset += addl_offset;
 
         // how many did we actually read?
-        let n = oci.fill_from_chunk(
-            chunk.blob,
-            addl_offset as u64,
-            &mut data[start..finish],
-            verity_data,
-        )?;
+        let n = match chunk.blob {
+            Some(blob) => oci.fill_from_chunk(
+                blob,
+                addl_offset as u64,
+                &mut data[start..finish],
+                verity_data,
+            )?,
+            // holes read as zeros
+            None => {
+                data[start..finish].fill(0);
+                to_read
+            }
+        };
         file_offset += n;
         buf_offset += n;
     }
@@ -72,9 +84,99 @@ pub(crate) fn file_read(
     Ok(buf_offset)
 }
 
+// like lseek with SEEK_DATA (or SEEK_HOLE when data is false): the offset of the first byte of data
+// (or of a hole, the end of the file being one) at or after offset
+pub(crate) fn seek_data_or_hole(inode: &Inode, offset: u64, data: bool) -> Result<u64> {
+    let chunks = match &inode.mode {
+        InodeMode::File { chunks } => chunks,
+        _ => return Err(WireFormatError::from_errno(Errno::EINVAL)),
+    };
+
+    let mut chunk_offset = 0;
+    for chunk in chunks {
+        let chunk_end = chunk_offset + chunk.len;
+        if chunk_end > offset && chunk.blob.is_some() == data {
+            return Ok(chunk_offset.max(offset));
+        }
+        chunk_offset = chunk_end;
+    }
+
+    if !data && offset < chunk_offset {
+        return Ok(chunk_offset);
+    }
+    Err(WireFormatError::from_errno(Errno::ENXIO))
+}
+
+fn open_metadata_blob(
+    oci: &Image,
+    verity_data: &Option<VerityData>,
+    md: &BlobRef,
+) -> Result<MetadataBlob> {
+    let digest = <Digest>::try_from(md)?;
+    let file_verity = if let Some(verity) = verity_data {
+        Some(
+            &verity
+                .get(&digest.underlying())
+                .ok_or(WireFormatError::InvalidFsVerityData(
+                    format!("missing verity data {digest}"),
+                    Backtrace::capture(),
+                ))?[..],
+        )
+    } else {
+        None
+    };
+    oci.open_metadata_blob(&digest, file_verity)
+}
+
+// the metadata of a layer: either a single blob with all of its inodes, or an index of shards,
+// each holding the inodes in a range of inode numbers. The blob of a shard is only opened the
+// first time an inode in its range is looked up, so that opening an image doesn't map all of them
+struct Layer {
+    blob: MetadataBlob,
+    shards: Vec<(MetadataShard, OnceLock<MetadataBlob>)>,
+}
+
+impl Layer {
+    fn find_inode(
+        &self,
+        ino: Ino,
+        open_blob: impl FnOnce(&BlobRef) -> Result<MetadataBlob>,
+    ) -> Result<Option<metadata_capnp::inode::Reader<'_>>> {
+        if self.shards.is_empty() {
+            return self.blob.find_inode(ino);
+        }
+
+        // the shards are sorted and don't overlap
+        let i = self
+            .shards
+            .partition_point(|(shard, _)| shard.last_ino < ino);
+        match self.shards.get(i) {
+            Some((shard, blob)) if shard.first_ino <= ino => {
+                let blob = match blob.get() {
+                    Some(blob) => blob,
+                    // another thread may open the shard at the same time, the first one wins
+                    None => {
+                        let opened = open_blob(&shard.blob)?;
+                        blob.get_or_init(|| opened)
+                    }
+                };
+                blob.find_inode(ino)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn max_ino(&self) -> Result<Option<Ino>> {
+        match self.shards.last() {
+            Some((shard, _)) => Ok(Some(shard.last_ino)),
+            None => self.blob.max_ino(),
+        }
+    }
+}
+
 pub struct PuzzleFS {
     pub oci: Arc<Image>,
-    layers: Vec<MetadataBlob>,
+    layers: Vec<Layer>,
     pub verity_data: Option<VerityData>,
     pub manifest_verity: Option<Vec<u8>>,
 }
@@ -82,63 +184,157 @@ pub struct PuzzleFS {
 impl PuzzleFS {
     pub fn open(oci: Image, tag: &str, manifest_verity: Option<&[u8]>) -> Result<PuzzleFS> {
         let rootfs = oci.open_rootfs_blob::<Noop>(tag, manifest_verity)?;
+        Self::from_rootfs(Arc::new(oci), &rootfs, manifest_verity)
+    }
 
-        if rootfs.manifest_version != PUZZLEFS_IMAGE_MANIFEST_VERSION {
+    pub(crate) fn from_rootfs(
+        oci: Arc<Image>,
+        rootfs: &Rootfs,
+        manifest_verity: Option<&[u8]>,
+    ) -> Result<PuzzleFS> {
+        if !(OLDEST_SUPPORTED_MANIFEST_VERSION..=PUZZLEFS_IMAGE_MANIFEST_VERSION)
+            .contains(&rootfs.manifest_version)
+        {
             return Err(WireFormatError::InvalidImageVersion(
                 format!(
-                    "got {}, expected {}",
-                    rootfs.manifest_version, PUZZLEFS_IMAGE_MANIFEST_VERSION
+                    "got {}, expected {} to {}",
+                    rootfs.manifest_version,
+                    OLDEST_SUPPORTED_MANIFEST_VERSION,
+                    PUZZLEFS_IMAGE_MANIFEST_VERSION
                 ),
                 Backtrace::capture(),
             ));
         }
 
         let verity_data = if manifest_verity.is_some() {
-            Some(rootfs.fs_verity_data)
+            Some(rootfs.fs_verity_data.clone())
         } else {
             None
         };
         let layers = rootfs
             .metadatas
             .iter()
-            .map(|md| -> Result<MetadataBlob> {
-                let digest = <Digest>::try_from(md)?;
-                let file_verity = if let Some(verity) = &verity_data {
-                    Some(
-                        &verity.get(&digest.underlying()).ok_or(
-                            WireFormatError::InvalidFsVerityData(
-                                format!("missing verity data {digest}"),
-                                Backtrace::capture(),
-                            ),
-                        )?[..],
-                    )
-                } else {
-                    None
-                };
-                oci.open_metadata_blob(&digest, file_verity)
+            .map(|md| -> Result<Layer> {
+                let blob = open_metadata_blob(&oci, &verity_data, md)?;
+                let shards = blob
+                    .shards()?
+                    .into_iter()
+                    .map(|shard| (shard, OnceLock::new()))
+                    .collect();
+                Ok(Layer { blob, shards })
             })
-            .collect::<Result<Vec<MetadataBlob>>>()?;
+            .collect::<Result<Vec<Layer>>>()?;
         Ok(PuzzleFS {
-            oci: Arc::new(oci),
+            oci,
             layers,
             verity_data,
             manifest_verity: manifest_verity.map(|e| e.to_vec()),
         })
     }
 
-    pub fn find_inode(&self, ino: u64) -> Result<Inode> {
+    fn layer_inode<'a>(
+        &self,
+        layer: &'a Layer,
+        ino: Ino,
+    ) -> Result<Option<metadata_capnp::inode::Reader<'a>>> {
+        layer.find_inode(ino, |md| {
+            open_metadata_blob(&self.oci, &self.verity_data, md)
+        })
+    }
+
+    // the topmost version of an inode in the layers from `from` down, and the layer it is in
+    fn find_layer_inode(&self, ino: Ino, from: usize) -> Result<Option<(usize, Inode)>> {
+        for (i, layer) in self.layers.iter().enumerate().skip(from) {
+            if let Some(inode) = self.layer_inode(layer, ino)? {
+                return Ok(Some((i, Inode::from_capnp(inode)?)));
+            }
+        }
+        Ok(None)
+    }
+
+    // whether the topmost version of an inode is a whiteout, or there is none at all
+    fn is_deleted(&self, ino: Ino) -> Result<bool> {
         for layer in self.layers.iter() {
-            if let Some(inode) = layer.find_inode(ino)? {
-                let inode = Inode::from_capnp(inode)?;
-                if let InodeMode::Wht = inode.mode {
-                    // TODO: seems like this should really be an Option.
-                    return Err(WireFormatError::from_errno(Errno::ENOENT));
+            if let Some(inode) = self.layer_inode(layer, ino)? {
+                return Ok(matches!(
+                    inode.get_mode().which(),
+                    Ok(metadata_capnp::inode::mode::Wht(()))
+                ));
+            }
+        }
+        Ok(true)
+    }
+
+    // a directory that looks below is merged with the same directory in the layers below it, down
+    // to the first one that is opaque. Entries hide the ones with the same name below them, and the
+    // entries of deleted inodes are whiteouts, which hide the name without showing up themselves
+    fn merge_dir(&self, ino: Ino, mut layer: usize, mut dir_list: DirList) -> Result<DirList> {
+        // there is nothing to merge with or hide in the bottom layer
+        if layer + 1 == self.layers.len() {
+            return Ok(dir_list);
+        }
+
+        let mut entries = BTreeMap::new();
+        loop {
+            for DirEnt { ino, name } in dir_list.entries {
+                entries.entry(name).or_insert(ino);
+            }
+            if !dir_list.look_below {
+                break;
+            }
+            match self.find_layer_inode(ino, layer + 1)? {
+                Some((
+                    below,
+                    Inode {
+                        mode:
+                            InodeMode::Dir {
+                                dir_list: below_list,
+                            },
+                        ..
+                    },
+                )) => {
+                    layer = below;
+                    dir_list = below_list;
                 }
-                return Ok(inode);
+                // the directory replaced something else, or nothing, below
+                _ => break,
             }
         }
 
-        Err(WireFormatError::from_errno(Errno::ENOENT))
+        let mut merged = Vec::with_capacity(entries.len());
+        for (name, ino) in entries {
+            if !self.is_deleted(ino)? {
+                merged.push(DirEnt { ino, name });
+            }
+        }
+        Ok(DirList {
+            look_below: false,
+            entries: merged,
+        })
+    }
+
+    /// Returns the inode as the layers describe it together: directories list the entries of all
+    /// the layers they look below into, minus the ones that were deleted.
+    pub fn find_inode(&self, ino: u64) -> Result<Inode> {
+        match self.find_layer_inode(ino, 0)? {
+            Some((
+                _,
+                Inode {
+                    mode: InodeMode::Wht,
+                    ..
+                },
+            ))
+            | None => Err(WireFormatError::from_errno(Errno::ENOENT)),
+            Some((layer, inode)) => match inode.mode {
+                InodeMode::Dir { dir_list } => Ok(Inode {
+                    mode: InodeMode::Dir {
+                        dir_list: self.merge_dir(ino, layer, dir_list)?,
+                    },
+                    ..inode
+                }),
+                _ => Ok(inode),
+            },
+        }
     }
 
     // lookup performs a path-based lookup in this puzzlefs
@@ -160,7 +356,12 @@ impl PuzzleFS {
                             .into_iter()
                             .find(|dir_entry| dir_entry.name == p.as_bytes())
                         {
-                            cur = self.find_inode(ino)?;
+                            cur = match self.find_inode(ino) {
+                                Ok(inode) => inode,
+                                // the entry was deleted by a whiteout in an upper layer
+                                Err(e) if e.to_errno() == Errno::ENOENT as i32 => return Ok(None),
+                                Err(e) => return Err(e),
+                            };
                             continue;
                         }
                     }
@@ -206,7 +407,7 @@ impl<'a> FileReader<'a> {
 
 impl io::Read for FileReader<'_> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let to_read = min(self.len - self.offset, buf.len());
+        let to_read = min(self.len.saturating_sub(self.offset), buf.len());
         if to_read == 0 {
             return Ok(0);
         }
@@ -224,15 +425,74 @@ impl io::Read for FileReader<'_> {
     }
 }
 
+impl io::Seek for FileReader<'_> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(delta) => (self.len as u64).checked_add_signed(delta),
+            io::SeekFrom::Current(delta) => (self.offset as u64).checked_add_signed(delta),
+        };
+        let offset = offset.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        self.offset = offset.try_into().map_err(io::Error::other)?;
+        Ok(offset)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use sha2::{Digest, Sha256};
     use tempfile::tempdir;
 
     use crate::builder::build_test_fs;
+    use crate::format::{FileChunk, Timespec};
 
     use super::*;
 
+    #[test]
+    fn test_seek_data_or_hole() {
+        let blob = BlobRef {
+            digest: [0; 32],
+            offset: 0,
+            compressed: false,
+        };
+        let chunk = |blob, len| FileChunk { blob, len };
+        let inode = Inode {
+            ino: 2,
+            mode: InodeMode::File {
+                chunks: vec![
+                    chunk(None, 10),
+                    chunk(Some(blob), 5),
+                    chunk(Some(blob), 5),
+                    chunk(None, 10),
+                    chunk(Some(blob), 5),
+                ],
+            },
+            uid: 0,
+            gid: 0,
+            permissions: 0o644,
+            atime: Timespec::default(),
+            mtime: Timespec::default(),
+            ctime: Timespec::default(),
+            nlink: 1,
+            additional: None,
+        };
+        let seek = |offset, data| seek_data_or_hole(&inode, offset, data).map_err(|e| e.to_errno());
+
+        assert_eq!(seek(0, true), Ok(10));
+        assert_eq!(seek(12, true), Ok(12));
+        assert_eq!(seek(20, true), Ok(30));
+        assert_eq!(seek(35, true), Err(Errno::ENXIO as i32));
+        assert_eq!(seek(0, false), Ok(0));
+        assert_eq!(seek(10, false), Ok(20));
+        assert_eq!(seek(30, false), Ok(35));
+        assert_eq!(seek(35, false), Err(Errno::ENXIO as i32));
+    }
+
     #[test]
     fn test_file_reader() {
         // make ourselves a test image
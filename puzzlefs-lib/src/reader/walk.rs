@@ -1,7 +1,9 @@
 use std::collections::VecDeque;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::format::{Inode, InodeMode, Result};
+use nix::errno::Errno;
+
+use crate::format::{Inode, InodeMode, Result, WireFormatError};
 use crate::oci::Image;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
@@ -19,16 +21,23 @@ pub struct WalkPuzzleFS<'a> {
 
 impl<'a> WalkPuzzleFS<'a> {
     pub fn walk(pfs: &'a mut PuzzleFS) -> Result<WalkPuzzleFS<'a>> {
-        let mut q = VecDeque::new();
-
         let inode = pfs.find_inode(1)?; // root inode number
-        let de = DirEntry {
-            oci: Arc::clone(&pfs.oci),
-            path: PathBuf::from("/"),
-            inode,
-        };
-        q.push_back(de);
-        Ok(WalkPuzzleFS { pfs, q })
+        Ok(Self::walk_inode(pfs, PathBuf::from("/"), inode))
+    }
+
+    /// Walks the subtree of an absolute path of the filesystem, starting with the path itself.
+    /// Fails with ENOENT if the filesystem doesn't have the path.
+    pub fn walk_from(pfs: &'a mut PuzzleFS, path: &Path) -> Result<WalkPuzzleFS<'a>> {
+        let inode = pfs
+            .lookup(path)?
+            .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+        Ok(Self::walk_inode(pfs, path.to_owned(), inode))
+    }
+
+    fn walk_inode(pfs: &'a mut PuzzleFS, path: PathBuf, inode: Inode) -> WalkPuzzleFS<'a> {
+        let mut q = VecDeque::new();
+        q.push_back(DirEntry::new(Arc::clone(&pfs.oci), path, inode));
+        WalkPuzzleFS { pfs, q }
     }
 
     fn add_dir_entries(&mut self, dir: &DirEntry) -> Result<()> {
@@ -64,6 +73,10 @@ pub struct DirEntry {
 }
 
 impl DirEntry {
+    pub(crate) fn new(oci: Arc<Image>, path: PathBuf, inode: Inode) -> Self {
+        DirEntry { oci, path, inode }
+    }
+
     /// Opens this DirEntry if it is a file.
     pub fn open(&self) -> Result<FileReader<'_>> {
         FileReader::new(&self.oci, &self.inode)